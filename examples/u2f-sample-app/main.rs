@@ -60,7 +60,7 @@ fn index() -> impl Reply {
 }
 
 fn register_request() -> impl warp::Reply {
-    let u2f = U2f::new(APP_ID.to_string());
+    let u2f = U2f::try_new(APP_ID.to_string()).unwrap();
 
     let challenge = u2f.generate_challenge().unwrap();
     let challenge_str = serde_json::to_string(&challenge).unwrap();
@@ -75,7 +75,7 @@ fn register_request() -> impl warp::Reply {
 }
 
 fn register_response(response: RegisterResponse, cookie: String) -> impl Reply {
-    let u2f = U2f::new(APP_ID.to_string());
+    let u2f = U2f::try_new(APP_ID.to_string()).unwrap();
 
     #[derive(Serialize)]
     struct Response {
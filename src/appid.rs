@@ -0,0 +1,308 @@
+// FIDO AppID and Facet specification.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-appid-and-facets-v1.2-ps-20170411.html
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+// The Public Suffix List, https://publicsuffix.org/list/public_suffix_list.dat, as published.
+const PUBLIC_SUFFIX_LIST: &str = include_str!("public_suffix_list.dat");
+
+// Single-label host accepted as AppID despite the implicit "*" rule, for local development.
+const LOCALHOST: &str = "localhost";
+
+// Punycode parameters, RFC 3492 section 5.
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+// Scheme, host and port of a URL. For web applications this is the FacetID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub scheme: String,
+    pub host: String,
+    // `None` for the default port of the scheme.
+    pub port: Option<u16>,
+}
+
+impl Origin {
+    // Parses the origin of `url`, ignoring any path, query or fragment.
+    pub fn parse(url: &str) -> Result<Origin> {
+        let (scheme, rest) = match url.find("://") {
+            Some(pos) => (&url[..pos], &url[pos + 3..]),
+            None => return Err(U2fError::InvalidAppId),
+        };
+
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+        if scheme.is_empty() || authority.is_empty() || authority.contains('@') {
+            return Err(U2fError::InvalidAppId);
+        }
+
+        // An IPv6 literal keeps its brackets, the port follows the closing one.
+        let port_start = match authority.rfind(']') {
+            Some(end) => authority[end..].find(':').map(|pos| end + pos),
+            None => authority.rfind(':'),
+        };
+        let (host, port) = match port_start {
+            Some(pos) => {
+                let port = authority[pos + 1..].parse::<u16>().map_err(|_e| U2fError::InvalidAppId)?;
+                (&authority[..pos], Some(port))
+            }
+            None => (authority, None),
+        };
+
+        let scheme = scheme.to_lowercase();
+        let host = host.trim_end_matches('.').to_lowercase();
+        if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c)) {
+            return Err(U2fError::InvalidAppId);
+        }
+
+        let port = match (scheme.as_str(), port) {
+            ("https", Some(443)) | ("http", Some(80)) => None,
+            (_, port) => port,
+        };
+
+        Ok(Origin { scheme, host, port })
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.scheme == "https"
+    }
+}
+
+// `scheme://host[:port]`, the form browsers put in the client data `origin`.
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+// An https AppID whose host is not itself a public suffix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppId {
+    app_id: String,
+    origin: Origin,
+}
+
+impl AppId {
+    pub fn parse(app_id: &str) -> Result<AppId> {
+        let origin = Origin::parse(app_id)?;
+
+        if !origin.is_https() || (is_public_suffix(&origin.host) && origin.host != LOCALHOST) {
+            return Err(U2fError::InvalidAppId);
+        }
+
+        Ok(AppId { app_id: app_id.to_string(), origin })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.app_id
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    // The FacetID of the web origin serving the AppID.
+    pub fn facet_id(&self) -> String {
+        self.origin.to_string()
+    }
+
+    // Whether a caller with `facet_id` may act for the AppID without consulting its trusted
+    // facet list: the caller must be an https origin sharing the AppID's host.
+    pub fn is_authorized(&self, facet_id: &str) -> bool {
+        match Origin::parse(facet_id) {
+            Ok(facet) => facet.is_https() && facet.host == self.origin.host,
+            Err(_) => false,
+        }
+    }
+
+    // Whether `facet_id` is an https origin with the same effective domain (eTLD+1) as the
+    // AppID, as required of the web facets listed in its trusted facet list.
+    pub fn shares_effective_domain(&self, facet_id: &str) -> bool {
+        let facet = match Origin::parse(facet_id) {
+            Ok(facet) if facet.is_https() => facet,
+            _ => return false,
+        };
+
+        match (effective_domain(&facet.host), effective_domain(&self.origin.host)) {
+            (Some(facet), Some(app_id)) => facet == app_id,
+            _ => facet.host == self.origin.host,
+        }
+    }
+}
+
+impl fmt::Display for AppId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.app_id)
+    }
+}
+
+// Whether `host` is a public suffix of the bundled list. Like the list's own algorithm, the
+// implicit "*" rule makes the last label of any host a public suffix, so single-label hosts
+// such as "internal" are public suffixes. IP addresses never are.
+pub fn is_public_suffix(host: &str) -> bool {
+    if is_ip_address(host) {
+        return false;
+    }
+
+    let labels: Vec<&str> = host.split('.').collect();
+    public_suffix_len(&labels) == labels.len()
+}
+
+// The public suffix of `host` plus one label, e.g. "example.co.uk" for "login.example.co.uk".
+// An IP address is its own effective domain; `None` is returned for a public suffix itself.
+pub fn effective_domain(host: &str) -> Option<String> {
+    if is_ip_address(host) {
+        return Some(host.to_string());
+    }
+
+    let labels: Vec<&str> = host.split('.').collect();
+    let suffix = public_suffix_len(&labels);
+    if labels.len() <= suffix {
+        return None;
+    }
+
+    Some(labels[labels.len() - suffix - 1..].join("."))
+}
+
+fn is_ip_address(host: &str) -> bool {
+    host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().is_ok()
+}
+
+// Number of trailing labels forming the public suffix: those of the prevailing rule of the
+// list, or the last label under the implicit "*" rule when no rule matches.
+fn public_suffix_len(labels: &[&str]) -> usize {
+    std::cmp::max(listed_suffix_len(labels), 1)
+}
+
+// Number of trailing labels covered by the longest matching rule of the list, an exception
+// rule taking precedence over any other. Zero when no rule matches.
+fn listed_suffix_len(labels: &[&str]) -> usize {
+    let rules = public_suffix_rules();
+    let suffix = |len: usize| labels[labels.len() - len..].join(".");
+
+    if let Some(len) = (1..=labels.len()).find(|len| rules.contains(&format!("!{}", suffix(*len)))) {
+        return len - 1;
+    }
+
+    (1..=labels.len())
+        .rev()
+        .find(|len| rules.contains(&suffix(*len)) || (*len > 1 && rules.contains(&format!("*.{}", suffix(len - 1)))))
+        .unwrap_or(0)
+}
+
+// Rules of the list as written ("com", "*.ck", "!www.ck"), internationalized labels
+// converted to the ASCII form hosts arrive in. Parsed on first use.
+fn public_suffix_rules() -> &'static HashSet<String> {
+    static RULES: OnceLock<HashSet<String>> = OnceLock::new();
+
+    RULES.get_or_init(|| {
+        PUBLIC_SUFFIX_LIST
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|rule| !rule.starts_with("//"))
+            .filter_map(|rule| {
+                let (exception, rule) = match rule.strip_prefix('!') {
+                    Some(rule) => ("!", rule),
+                    None => ("", rule),
+                };
+                let labels = rule.split('.').map(label_to_ascii).collect::<Option<Vec<String>>>()?;
+                Some(format!("{}{}", exception, labels.join(".")))
+            })
+            .collect()
+    })
+}
+
+// The "xn--" form of an internationalized label. The list is already normalized, so this is
+// plain Punycode without further IDNA mapping.
+fn label_to_ascii(label: &str) -> Option<String> {
+    if label.is_ascii() {
+        return Some(label.to_string());
+    }
+
+    punycode(label).map(|encoded| format!("xn--{}", encoded))
+}
+
+// RFC 3492 encoding, `None` on overflow.
+fn punycode(input: &str) -> Option<String> {
+    let code_points: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(char::is_ascii).collect();
+
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let (mut n, mut delta, mut bias, mut handled) = (INITIAL_N, 0u32, INITIAL_BIAS, basic);
+    while (handled as usize) < code_points.len() {
+        let m = *code_points.iter().filter(|c| **c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for c in &code_points {
+            if *c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if *c != n {
+                continue;
+            }
+
+            let mut q = delta;
+            let mut k = BASE;
+            loop {
+                let t = if k <= bias { TMIN } else if k >= bias + TMAX { TMAX } else { k - bias };
+                if q < t {
+                    break;
+                }
+                output.push(punycode_digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            output.push(punycode_digit(q));
+
+            bias = punycode_adapt(delta, handled + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn punycode_digit(digit: u32) -> char {
+    match digit {
+        0..=25 => (b'a' + digit as u8) as char,
+        _ => (b'0' + (digit - 26) as u8) as char,
+    }
+}
+
+fn punycode_adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
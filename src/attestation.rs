@@ -0,0 +1,241 @@
+use std::path::Path;
+
+use chrono::prelude::*;
+
+use crate::crypto_provider::{default_provider, CryptoProvider};
+use crate::der;
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+const BASIC_CONSTRAINTS: &str = "2.5.29.19";
+// Longest path from an attestation certificate to a root, both included.
+const MAX_CHAIN_LENGTH: usize = 8;
+
+// Decides which attestation certificates are accepted during registration.
+#[derive(Clone, Debug, Default)]
+pub enum AttestationPolicy {
+    // Any P-256 attestation certificate that signed the registration.
+    #[default]
+    Any,
+    // The attestation certificate must chain to a root of the trust store.
+    TrustedChain,
+    // A trusted chain, or a self-signed certificate from one of the listed issuers (common names).
+    SelfAttestationFor(Vec<String>),
+}
+
+// Root and intermediate certificates used to validate attestation certificates, DER encoded.
+#[derive(Debug, Default)]
+pub struct AttestationTrustStore {
+    roots: Vec<Vec<u8>>,
+    intermediates: Vec<Vec<u8>>,
+}
+
+impl AttestationTrustStore {
+    pub fn new() -> Self {
+        AttestationTrustStore::default()
+    }
+
+    pub fn add_root_der(&mut self, der: &[u8]) -> Result<()> {
+        self.roots.push(certificate(der)?);
+        Ok(())
+    }
+
+    // Adds every certificate found in the PEM document.
+    pub fn add_root_pem(&mut self, pem: &[u8]) -> Result<()> {
+        self.roots.extend(pem_certificates(pem)?);
+        Ok(())
+    }
+
+    // Reads a PEM or DER encoded root certificate file.
+    pub fn add_root_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let certs = read_certificates(path.as_ref())?;
+        self.roots.extend(certs);
+        Ok(())
+    }
+
+    pub fn add_intermediate_der(&mut self, der: &[u8]) -> Result<()> {
+        self.intermediates.push(certificate(der)?);
+        Ok(())
+    }
+
+    // Adds every certificate found in the PEM document.
+    pub fn add_intermediate_pem(&mut self, pem: &[u8]) -> Result<()> {
+        self.intermediates.extend(pem_certificates(pem)?);
+        Ok(())
+    }
+
+    // Reads a PEM or DER encoded intermediate certificate file.
+    pub fn add_intermediate_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let certs = read_certificates(path.as_ref())?;
+        self.intermediates.extend(certs);
+        Ok(())
+    }
+
+    // Checks that the DER encoded attestation certificate chains to one of the roots.
+    pub fn verify(&self, attestation_cert: &[u8]) -> Result<()> {
+        self.verify_with(&*default_provider(), attestation_cert)
+    }
+
+    // Like `verify`, checking signatures with `crypto`.
+    pub fn verify_with(&self, crypto: &dyn CryptoProvider, attestation_cert: &[u8]) -> Result<()> {
+        if !verify_chain(crypto, attestation_cert, &self.roots, &self.intermediates)? {
+            return Err(U2fError::NotTrustedAnchor);
+        }
+
+        Ok(())
+    }
+
+    // Applies `policy` to the DER encoded attestation certificate.
+    pub fn check(&self, policy: &AttestationPolicy, attestation_cert: &[u8]) -> Result<()> {
+        self.check_with(&*default_provider(), policy, attestation_cert)
+    }
+
+    // Like `check`, checking signatures with `crypto`.
+    pub fn check_with(&self, crypto: &dyn CryptoProvider, policy: &AttestationPolicy, attestation_cert: &[u8]) -> Result<()> {
+        match policy {
+            AttestationPolicy::Any => Ok(()),
+            AttestationPolicy::TrustedChain => self.verify_with(crypto, attestation_cert),
+            AttestationPolicy::SelfAttestationFor(issuers) => {
+                if self.verify_with(crypto, attestation_cert).is_ok() {
+                    return Ok(());
+                }
+
+                let fields = der::certificate_fields(attestation_cert).map_err(|_e| U2fError::BadCertificate)?;
+                let issuer = crypto.parse_certificate(attestation_cert)?.issuer;
+                let listed = issuer.is_some_and(|issuer| issuers.contains(&issuer));
+
+                if listed && fields.subject == fields.issuer && crypto.verify_certificate(attestation_cert, attestation_cert)? {
+                    Ok(())
+                } else {
+                    Err(U2fError::NotTrustedAnchor)
+                }
+            }
+        }
+    }
+}
+
+// Whether `cert` chains to one of `roots` through `intermediates`, all DER encoded. Roots are
+// trust anchors: neither their signature nor their basic constraints are checked unless they
+// issue another certificate of the path. Every certificate of the path must be valid now.
+pub(crate) fn verify_chain(crypto: &dyn CryptoProvider, cert: &[u8], roots: &[Vec<u8>], intermediates: &[Vec<u8>]) -> Result<bool> {
+    let now = Utc::now();
+
+    let mut cert = cert;
+    for _ in 0..MAX_CHAIN_LENGTH {
+        if !is_valid_at(cert, now)? {
+            return Ok(false);
+        }
+
+        if roots.iter().any(|root| root[..] == *cert) {
+            return Ok(true);
+        }
+
+        if let Some(root) = find_issuer(crypto, cert, roots)? {
+            return is_valid_at(root, now);
+        }
+
+        cert = match find_issuer(crypto, cert, intermediates)? {
+            Some(intermediate) => intermediate,
+            None => return Ok(false),
+        };
+    }
+
+    Ok(false)
+}
+
+// The first of `candidates` that is a CA named as the issuer of `cert` and whose key signed it.
+fn find_issuer<'a>(crypto: &dyn CryptoProvider, cert: &[u8], candidates: &'a [Vec<u8>]) -> Result<Option<&'a [u8]>> {
+    let issuer = der::certificate_fields(cert).map_err(|_e| U2fError::BadCertificate)?.issuer;
+
+    for candidate in candidates {
+        let subject = der::certificate_fields(candidate).map_err(|_e| U2fError::BadCertificate)?.subject;
+        if subject != issuer || !is_ca(candidate)? {
+            continue;
+        }
+
+        match crypto.verify_certificate(cert, candidate) {
+            Ok(true) => return Ok(Some(candidate)),
+            // A candidate the provider cannot check is simply not a match.
+            Ok(false) | Err(U2fError::UnsupportedAlgorithm) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(None)
+}
+
+fn is_ca(cert: &[u8]) -> Result<bool> {
+    let extensions = der::certificate_extensions(cert).map_err(|_e| U2fError::BadCertificate)?;
+
+    let basic_constraints = match extensions.iter().find(|(oid, _, _)| oid == BASIC_CONSTRAINTS) {
+        Some((_, _, value)) => *value,
+        None => return Ok(false),
+    };
+
+    // BasicConstraints ::= SEQUENCE { cA BOOLEAN DEFAULT FALSE, pathLenConstraint INTEGER OPTIONAL }
+    let (fields, _) = der::expect(basic_constraints, der::SEQUENCE).map_err(|_e| U2fError::BadCertificate)?;
+    Ok(matches!(der::expect(fields, der::BOOLEAN), Ok(([flag], _)) if *flag != 0))
+}
+
+// Certificates whose validity cannot be read are never valid.
+fn is_valid_at(cert: &[u8], now: DateTime<Utc>) -> Result<bool> {
+    let validity = der::certificate_fields(cert).map_err(|_e| U2fError::BadCertificate)?.validity;
+
+    let (not_before, not_after) = match der::read_tlv(validity) {
+        Ok((tag, content, rest)) => (der::parse_time(tag, content), rest),
+        Err(_) => return Ok(false),
+    };
+    let not_after = match der::read_tlv(not_after) {
+        Ok((tag, content, _)) => der::parse_time(tag, content),
+        Err(_) => return Ok(false),
+    };
+
+    Ok(match (not_before, not_after) {
+        (Some(not_before), Some(not_after)) => not_before <= now && now <= not_after,
+        _ => false,
+    })
+}
+
+// Checks that `der` parses before it enters the store.
+fn certificate(der: &[u8]) -> Result<Vec<u8>> {
+    default_provider().parse_certificate(der)?;
+    Ok(der.to_vec())
+}
+
+// DER contents of the CERTIFICATE blocks of a PEM document.
+fn pem_certificates(pem: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let pem = std::str::from_utf8(pem).map_err(|_e| U2fError::BadCertificate)?;
+
+    let mut certs = vec![];
+    let mut block: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        match (line, block.as_mut()) {
+            ("-----BEGIN CERTIFICATE-----", None) => block = Some(String::new()),
+            ("-----END CERTIFICATE-----", Some(base64)) => {
+                let der = base64::decode_config(base64, base64::STANDARD).map_err(|_e| U2fError::BadCertificate)?;
+                certs.push(certificate(&der)?);
+                block = None;
+            }
+            (line, Some(base64)) => base64.push_str(line),
+            (_, None) => (),
+        }
+    }
+
+    if certs.is_empty() || block.is_some() {
+        return Err(U2fError::BadCertificate);
+    }
+
+    Ok(certs)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let data = std::fs::read(path).map_err(U2fError::IoError)?;
+
+    if data.starts_with(b"-----BEGIN") {
+        pem_certificates(&data)
+    } else {
+        Ok(vec![certificate(&data)?])
+    }
+}
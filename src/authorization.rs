@@ -1,52 +1,102 @@
 use bytes::{Buf, BufMut};
 use std::io::Cursor;
-use openssl::sha::sha256;
 
 
+use crate::util::*;
+use crate::appid::AppId;
+use crate::messages::TrustedFacetsList;
 use crate::u2ferror::U2fError;
+use crate::crypto_provider::{default_provider, CryptoProvider};
 
 
 /// The `Result` type used in this crate.
 type Result<T> = ::std::result::Result<T, U2fError>;
 
-#[derive(Serialize, Clone)]
+// Bit 0 of the user presence byte is set when the user touched the token.
+const USER_PRESENCE_BIT: u8 = 0x01;
+
+#[derive(Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Authorization {
     pub counter: u32,
     pub user_presence: bool,
+    // Set when the counter did not increase, which may indicate a cloned authenticator.
+    pub possible_clone: bool,
+}
+
+// How the signature counter reported by the token is compared with the stored one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CounterPolicy {
+    // The counter must be strictly greater than the stored value.
+    #[default]
+    StrictIncrease,
+    // The counter may repeat the stored value, which is still reported as a possible clone,
+    // but must not go back.
+    AllowEqual,
+    // Like `StrictIncrease`, but tokens that always report 0 are accepted.
+    IgnoreZeroCounter,
+    // Never reject, only flag a counter that did not increase.
+    WarnOnly,
+}
+
+impl CounterPolicy {
+    // Returns whether the counter indicates a possible clone, or `CounterTooLow` if the policy rejects it.
+    pub fn check(&self, stored: u32, received: u32) -> Result<bool> {
+        let possible_clone = received <= stored;
+
+        match self {
+            CounterPolicy::StrictIncrease if possible_clone => Err(U2fError::CounterTooLow),
+            CounterPolicy::AllowEqual if received < stored => Err(U2fError::CounterTooLow),
+            CounterPolicy::IgnoreZeroCounter if stored == 0 && received == 0 => Ok(false),
+            CounterPolicy::IgnoreZeroCounter if possible_clone => Err(U2fError::CounterTooLow),
+            _ => Ok(possible_clone),
+        }
+    }
 }
 
-pub fn parse_sign_response(app_id: String, client_data: Vec<u8>, public_key: Vec<u8>, sign_data: Vec<u8>) -> Result<Authorization> {
+pub fn parse_sign_response(app_id: String, challenge: String, client_data: Vec<u8>, public_key: Vec<u8>, sign_data: Vec<u8>) -> Result<Authorization> {
+    parse_sign_response_with(&*default_provider(), app_id, challenge, client_data, public_key, sign_data)
+}
+
+// `parse_sign_response` on the given crypto backend.
+pub fn parse_sign_response_with(crypto: &dyn CryptoProvider, app_id: String, challenge: String, client_data: Vec<u8>, public_key: Vec<u8>, sign_data: Vec<u8>) -> Result<Authorization> {
+    verify_sign_response(crypto, &AppId::parse(&app_id)?, None, &challenge, &client_data, &public_key, &sign_data)
+}
+
+// `parse_sign_response_with` for an app ID parsed beforehand, accepting the origins of
+// `trusted_facets` when given.
+pub(crate) fn verify_sign_response(crypto: &dyn CryptoProvider, app_id: &AppId, trusted_facets: Option<&TrustedFacetsList>, challenge: &str, client_data: &[u8], public_key: &[u8], sign_data: &[u8]) -> Result<Authorization> {
 
     if sign_data.len() <= 5 {
         return Err(U2fError::InvalidSignatureData)
     }
 
+    verify_client_data(client_data, SIGN_TYPE, challenge, app_id, trusted_facets)?;
+
     let user_presence_flag = &sign_data[0];
     let counter = &sign_data[1..=4];
     let signature = &sign_data[5..];
 
     // Let's build the msg to verify the signature
-    let app_id_hash = sha256(&app_id.into_bytes());
-    let client_data_hash = sha256(&client_data[..]);
+    let app_id_hash = crypto.sha256(app_id.as_str().as_bytes());
+    let client_data_hash = crypto.sha256(client_data);
 
     let mut msg = vec![];
     msg.put(app_id_hash.as_ref());
-    msg.put(user_presence_flag.clone()); 
-    msg.put(counter.clone());  
+    msg.put(*user_presence_flag);
+    msg.put(counter);
     msg.put(client_data_hash.as_ref());
 
-    let public_key = super::crypto::NISTP256Key::from_bytes(&public_key)?;
-
     // The signature is to be verified by the relying party using the public key obtained during registration.
-    let verified = public_key.verify_signature(&signature[..], msg.as_ref())?;
+    let verified = crypto.verify_p256(public_key, msg.as_ref(), signature)?;
     if !verified {
         return Err(U2fError::BadSignature)
     }
 
     let authorization = Authorization {
         counter: get_counter(counter),
-        user_presence: true
+        user_presence: user_presence_flag & USER_PRESENCE_BIT == USER_PRESENCE_BIT,
+        possible_clone: false,
     };
 
     Ok(authorization)
@@ -54,6 +104,6 @@ pub fn parse_sign_response(app_id: String, client_data: Vec<u8>, public_key: Vec
 
 
 fn get_counter(counter: &[u8]) -> u32 {
-    let mut buf = Cursor::new(&counter[..]);
+    let mut buf = Cursor::new(counter);
     buf.get_u32_be()
 }
\ No newline at end of file
@@ -0,0 +1,199 @@
+// U2F over Bluetooth Low Energy framing. No BLE stack is involved: fragments are the values
+// written to the U2F Control Point and notified on the U2F Status characteristic.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-bt-protocol-v1.2-ps-20170411.html
+
+use crate::raw::{ApduEncoding, Request, Response};
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub const CMD_PING: u8 = 0x81;
+pub const CMD_KEEPALIVE: u8 = 0x82;
+pub const CMD_MSG: u8 = 0x83;
+pub const CMD_ERROR: u8 = 0xbf;
+
+// Bounds of the U2F Control Point Length characteristic.
+pub const MIN_CONTROL_POINT_LENGTH: usize = 20;
+pub const MAX_CONTROL_POINT_LENGTH: usize = 512;
+pub const MAX_MESSAGE_SIZE: usize = 0xffff;
+pub const MAX_SEQ: u8 = 0x7f;
+
+pub const KEEPALIVE_PROCESSING: u8 = 0x01;
+pub const KEEPALIVE_TUP_NEEDED: u8 = 0x02;
+
+pub const ERR_INVALID_CMD: u8 = 0x01;
+pub const ERR_INVALID_PAR: u8 = 0x02;
+pub const ERR_INVALID_LEN: u8 = 0x03;
+pub const ERR_INVALID_SEQ: u8 = 0x04;
+pub const ERR_REQ_TIMEOUT: u8 = 0x05;
+pub const ERR_OTHER: u8 = 0x7f;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Ping,
+    Keepalive,
+    Msg,
+    Error,
+}
+
+impl Command {
+    pub fn from_byte(byte: u8) -> Result<Command> {
+        match byte {
+            CMD_PING => Ok(Command::Ping),
+            CMD_KEEPALIVE => Ok(Command::Keepalive),
+            CMD_MSG => Ok(Command::Msg),
+            CMD_ERROR => Ok(Command::Error),
+            _ => Err(U2fError::BleError(ERR_INVALID_CMD)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Command::Ping => CMD_PING,
+            Command::Keepalive => CMD_KEEPALIVE,
+            Command::Msg => CMD_MSG,
+            Command::Error => CMD_ERROR,
+        }
+    }
+}
+
+// A command frame, before fragmentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub command: Command,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(command: Command, data: Vec<u8>) -> Self {
+        Message { command, data }
+    }
+
+    pub fn ping(data: Vec<u8>) -> Self {
+        Message::new(Command::Ping, data)
+    }
+
+    // CMD_MSG carrying the request as an extended length APDU.
+    pub fn apdu(request: &Request) -> Result<Self> {
+        Ok(Message::new(Command::Msg, request.encode(ApduEncoding::Extended)?))
+    }
+
+    // The APDU response of a CMD_MSG exchange. CMD_ERROR replies become `BleError`.
+    pub fn into_response(self) -> Result<Response> {
+        match self.command {
+            Command::Msg => Response::decode(&self.data),
+            Command::Error => Err(U2fError::BleError(self.data.first().copied().unwrap_or(ERR_OTHER))),
+            _ => Err(U2fError::InvalidBleFrame),
+        }
+    }
+
+    // Status byte of a CMD_KEEPALIVE frame.
+    pub fn keepalive_status(&self) -> Option<u8> {
+        match self.command {
+            Command::Keepalive => self.data.first().copied(),
+            _ => None,
+        }
+    }
+
+    // Splits the frame into fragments of at most `control_point_length` bytes: an initial
+    // fragment with the command and length, then continuation fragments whose sequence
+    // number wraps after MAX_SEQ.
+    pub fn to_fragments(&self, control_point_length: usize) -> Result<Vec<Vec<u8>>> {
+        check_control_point_length(control_point_length)?;
+        if self.data.len() > MAX_MESSAGE_SIZE {
+            return Err(U2fError::BleError(ERR_INVALID_LEN));
+        }
+
+        let split = std::cmp::min(self.data.len(), control_point_length - 3);
+
+        let mut fragment = vec![self.command.to_byte()];
+        fragment.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        fragment.extend_from_slice(&self.data[..split]);
+
+        let mut fragments = vec![fragment];
+        for (seq, chunk) in self.data[split..].chunks(control_point_length - 1).enumerate() {
+            let mut fragment = vec![seq as u8 & MAX_SEQ];
+            fragment.extend_from_slice(chunk);
+            fragments.push(fragment);
+        }
+
+        Ok(fragments)
+    }
+}
+
+fn check_control_point_length(length: usize) -> Result<()> {
+    if !(MIN_CONTROL_POINT_LENGTH..=MAX_CONTROL_POINT_LENGTH).contains(&length) {
+        return Err(U2fError::InvalidBleFrame);
+    }
+    Ok(())
+}
+
+// Reassembles frames from fragments, checking continuation sequence numbers.
+pub struct Decoder {
+    control_point_length: usize,
+    partial: Option<(Message, usize)>,
+    seq: u8,
+}
+
+impl Decoder {
+    // `control_point_length` is the value of the U2F Control Point Length characteristic.
+    pub fn new(control_point_length: usize) -> Result<Self> {
+        check_control_point_length(control_point_length)?;
+        Ok(Decoder { control_point_length, partial: None, seq: 0 })
+    }
+
+    // Feeds one fragment, returning the frame it completes.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Message>> {
+        if fragment.is_empty() || fragment.len() > self.control_point_length {
+            return Err(U2fError::InvalidBleFrame);
+        }
+
+        if fragment[0] & 0x80 != 0 {
+            if self.partial.take().is_some() {
+                return Err(U2fError::BleError(ERR_INVALID_SEQ));
+            }
+            if fragment.len() < 3 {
+                return Err(U2fError::InvalidBleFrame);
+            }
+
+            let command = Command::from_byte(fragment[0])?;
+            let length = u16::from_be_bytes([fragment[1], fragment[2]]) as usize;
+            if fragment.len() - 3 > length {
+                return Err(U2fError::BleError(ERR_INVALID_LEN));
+            }
+
+            let message = Message::new(command, fragment[3..].to_vec());
+            if message.data.len() == length {
+                return Ok(Some(message));
+            }
+
+            self.partial = Some((message, length));
+            self.seq = 0;
+            return Ok(None);
+        }
+
+        let (message, length) = match self.partial.as_mut() {
+            Some(partial) => partial,
+            None => return Err(U2fError::InvalidBleFrame),
+        };
+
+        if fragment[0] != self.seq {
+            self.partial = None;
+            return Err(U2fError::BleError(ERR_INVALID_SEQ));
+        }
+        if message.data.len() + fragment.len() - 1 > *length {
+            self.partial = None;
+            return Err(U2fError::BleError(ERR_INVALID_LEN));
+        }
+
+        message.data.extend_from_slice(&fragment[1..]);
+        self.seq = (self.seq + 1) & MAX_SEQ;
+
+        if message.data.len() < *length {
+            return Ok(None);
+        }
+
+        Ok(self.partial.take().map(|(message, _)| message))
+    }
+}
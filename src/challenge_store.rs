@@ -0,0 +1,178 @@
+// Server side record of issued challenges, so that each one completes at most one
+// registration or authentication.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use chrono::prelude::*;
+
+use crate::crypto_provider::default_provider;
+use crate::protocol::Challenge;
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+// Extension of challenge files still being written by `FileChallengeStore::issue`.
+const TEMP_EXTENSION: &str = "tmp";
+
+// Challenges are told apart by app ID and challenge string together, so that U2f instances
+// for different app IDs can share a store.
+pub trait ChallengeStore: Send + Sync {
+    // Records a challenge that may be consumed until `expires_at`.
+    fn issue(&self, challenge: &Challenge, expires_at: DateTime<Utc>) -> Result<()>;
+
+    // Removes the challenge in a single step, so that concurrent callers cannot both succeed.
+    // Fails with `ChallengeReplayed` when it was never issued or is already consumed, and with
+    // `ChallengeExpired` when it was issued but is no longer valid at `now`.
+    fn consume(&self, challenge: &Challenge, now: DateTime<Utc>) -> Result<()>;
+
+    // Forgets the challenges that expired before `now`.
+    fn expire(&self, now: DateTime<Utc>) -> Result<()>;
+}
+
+// Challenges held in process memory, for single instance deployments.
+#[derive(Debug, Default)]
+pub struct MemoryChallengeStore {
+    challenges: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+}
+
+impl MemoryChallengeStore {
+    pub fn new() -> Self {
+        MemoryChallengeStore::default()
+    }
+}
+
+impl ChallengeStore for MemoryChallengeStore {
+    fn issue(&self, challenge: &Challenge, expires_at: DateTime<Utc>) -> Result<()> {
+        let mut challenges = self.challenges.lock().unwrap_or_else(PoisonError::into_inner);
+        challenges.insert(memory_key(challenge), expires_at);
+        Ok(())
+    }
+
+    fn consume(&self, challenge: &Challenge, now: DateTime<Utc>) -> Result<()> {
+        let expires_at = self
+            .challenges
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&memory_key(challenge))
+            .ok_or(U2fError::ChallengeReplayed)?;
+
+        check_expiry(expires_at, now)
+    }
+
+    fn expire(&self, now: DateTime<Utc>) -> Result<()> {
+        let mut challenges = self.challenges.lock().unwrap_or_else(PoisonError::into_inner);
+        challenges.retain(|_, expires_at| *expires_at >= now);
+        Ok(())
+    }
+}
+
+fn memory_key(challenge: &Challenge) -> (String, String) {
+    (challenge.app_id.clone(), challenge.challenge.clone())
+}
+
+// One file per challenge in a directory, named after the app ID and challenge and holding
+// its expiry. Files are written under a temporary name and renamed into place, and removing
+// one is the atomic consume step, so several processes may share the directory as long as
+// the file system provides atomic rename and unlink (any local one does).
+#[derive(Clone, Debug)]
+pub struct FileChallengeStore {
+    dir: PathBuf,
+}
+
+impl FileChallengeStore {
+    // Stores challenges in `dir`, creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref()).map_err(U2fError::IoError)?;
+        Ok(FileChallengeStore { dir: dir.as_ref().to_path_buf() })
+    }
+
+    // `<hex SHA-256 of the app ID>.<challenge>`. Challenges are unpadded base64url, which is
+    // safe in a file name; anything else is refused rather than joined to the directory.
+    fn file_name(challenge: &Challenge) -> Result<String> {
+        let name = &challenge.challenge;
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(U2fError::InvalidChallenge);
+        }
+
+        let app_id = hex::encode(default_provider().sha256(challenge.app_id.as_bytes()));
+        Ok(format!("{}.{}", app_id, name))
+    }
+
+    fn path(&self, challenge: &Challenge) -> Result<PathBuf> {
+        Ok(self.dir.join(FileChallengeStore::file_name(challenge)?))
+    }
+}
+
+impl ChallengeStore for FileChallengeStore {
+    fn issue(&self, challenge: &Challenge, expires_at: DateTime<Utc>) -> Result<()> {
+        let name = FileChallengeStore::file_name(challenge)?;
+        let path = self.dir.join(&name);
+        let temp_path = self.dir.join(format!("{}.{}", name, TEMP_EXTENSION));
+
+        // A reader never sees a partially written expiry.
+        let written = fs::File::create(&temp_path)
+            .and_then(|mut file| file.write_all(expires_at.to_rfc3339().as_bytes()))
+            .and_then(|()| fs::rename(&temp_path, &path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(U2fError::IoError(e));
+        }
+
+        Ok(())
+    }
+
+    fn consume(&self, challenge: &Challenge, now: DateTime<Utc>) -> Result<()> {
+        let path = self.path(challenge)?;
+
+        let expires_at = read_expiry(&path)?.ok_or(U2fError::ChallengeReplayed)?;
+        match fs::remove_file(&path) {
+            Ok(()) => check_expiry(expires_at, now),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(U2fError::ChallengeReplayed),
+            Err(e) => Err(U2fError::IoError(e)),
+        }
+    }
+
+    fn expire(&self, now: DateTime<Utc>) -> Result<()> {
+        for entry in fs::read_dir(&self.dir).map_err(U2fError::IoError)? {
+            let path = entry.map_err(U2fError::IoError)?.path();
+            if path.extension().is_some_and(|extension| extension == TEMP_EXTENSION) {
+                continue;
+            }
+
+            // Files consumed concurrently are simply gone by now.
+            let expired = read_expiry(&path)?.is_some_and(|expires_at| expires_at < now);
+            if expired {
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(U2fError::IoError(e)),
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Expiry recorded in a challenge file, or `None` if there is no such file.
+fn read_expiry(path: &Path) -> Result<Option<DateTime<Utc>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(U2fError::IoError(e)),
+    };
+
+    let expires_at = DateTime::parse_from_rfc3339(content.trim()).map_err(|_e| U2fError::InvalidTimestamp)?;
+    Ok(Some(expires_at.with_timezone(&Utc)))
+}
+
+fn check_expiry(expires_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<()> {
+    if now > expires_at {
+        return Err(U2fError::ChallengeExpired);
+    }
+    Ok(())
+}
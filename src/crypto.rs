@@ -7,12 +7,12 @@
 
 #![allow(non_camel_case_types)]
 
-use openssl::{bn, ec, hash, nid, sign, x509};
+use openssl::{bn, ec, hash, nid, sign, symm, x509};
 use std::convert::TryFrom;
 
 // use super::constants::*;
 use crate::u2ferror::U2fError;
-use openssl::pkey::Public;
+use openssl::pkey::{Private, Public};
 
 // use super::proto::*;
 
@@ -45,12 +45,66 @@ impl TryFrom<&[u8]> for X509PublicKey {
 
     // Must be DER bytes. If you have PEM, base64decode first!
     fn try_from(d: &[u8]) -> Result<Self, Self::Error> {
-        let pubk = x509::X509::from_der(d).map_err(|e| U2fError::OpenSSLError(e))?;
-        Ok(X509PublicKey { pubk: pubk })
+        let pubk = x509::X509::from_der(d).map_err(U2fError::OpenSSLError)?;
+        Ok(X509PublicKey { pubk })
     }
 }
 
 impl X509PublicKey {
+    /// Whether `issuer`'s key produced the certificate signature.
+    pub(crate) fn is_signed_by(&self, issuer: &X509PublicKey) -> Result<bool, U2fError> {
+        let pkey = issuer
+            .pubk
+            .public_key()
+            .map_err(U2fError::OpenSSLError)?;
+
+        self.pubk.verify(&pkey).map_err(U2fError::OpenSSLError)
+    }
+
+    /// Uncompressed point of the subject public key, if it is a P-256 key.
+    pub(crate) fn p256_public_key(&self) -> Result<Option<Vec<u8>>, U2fError> {
+        let pkey = self
+            .pubk
+            .public_key()
+            .map_err(U2fError::OpenSSLError)?;
+
+        let ec_key = match pkey.ec_key() {
+            Ok(ec_key) => ec_key,
+            Err(_) => return Ok(None),
+        };
+
+        if ec_key.group().curve_name() != Some(nid::Nid::X9_62_PRIME256V1) {
+            return Ok(None);
+        }
+
+        let mut ctx = bn::BigNumContext::new().map_err(U2fError::OpenSSLError)?;
+        ec_key
+            .public_key()
+            .to_bytes(ec_key.group(), ec::PointConversionForm::UNCOMPRESSED, &mut ctx)
+            .map(Some)
+            .map_err(U2fError::OpenSSLError)
+    }
+
+    /// Subject public key bits: the uncompressed point of EC keys, the PKCS#1
+    /// encoding of RSA keys.
+    pub(crate) fn public_key_bits(&self) -> Result<Vec<u8>, U2fError> {
+        let pkey = self
+            .pubk
+            .public_key()
+            .map_err(U2fError::OpenSSLError)?;
+
+        if let Ok(ec_key) = pkey.ec_key() {
+            let mut ctx = bn::BigNumContext::new().map_err(U2fError::OpenSSLError)?;
+            ec_key
+                .public_key()
+                .to_bytes(ec_key.group(), ec::PointConversionForm::UNCOMPRESSED, &mut ctx)
+                .map_err(U2fError::OpenSSLError)
+        } else {
+            let rsa = pkey.rsa().map_err(U2fError::OpenSSLError)?;
+            rsa.public_key_to_der_pkcs1().map_err(U2fError::OpenSSLError)
+        }
+    }
+
     pub (crate) fn subject_name(&self) -> Option<String> {
         let cert = &self.pubk;
 
@@ -81,28 +135,6 @@ impl X509PublicKey {
         }
     }
 
-    pub(crate) fn is_secp256r1(&self) -> Result<bool, U2fError> {
-        // Can we get the public key?
-        let pk = self
-            .pubk
-            .public_key()
-            .map_err(|e| U2fError::OpenSSLError(e))?;
-
-        let ec_key = pk.ec_key().map_err(|e| U2fError::OpenSSLError(e))?;
-
-        ec_key
-            .check_key()
-            .map_err(|e| U2fError::OpenSSLError(e))?;
-
-        let ec_grpref = ec_key.group();
-
-        let ec_curve = ec_grpref
-            .curve_name()
-            .ok_or(U2fError::OpenSSLNoCurveName)?;
-
-        Ok(ec_curve == nid::Nid::X9_62_PRIME256V1)
-    }
-
     pub(crate) fn verify_signature(
         &self,
         signature: &[u8],
@@ -111,20 +143,83 @@ impl X509PublicKey {
         let pkey = self
             .pubk
             .public_key()
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
 
         // TODO: Should this determine the hash type from the x509 cert? Or other?
         let mut verifier = sign::Verifier::new(hash::MessageDigest::sha256(), &pkey)
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
         verifier
             .update(verification_data)
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
         verifier
             .verify(signature)
-            .map_err(|e| U2fError::OpenSSLError(e))
+            .map_err(U2fError::OpenSSLError)
     }
 }
 
+/// A new P-256 private key, as a 32 byte big-endian scalar.
+pub(crate) fn generate_p256_key() -> Result<Vec<u8>, U2fError> {
+    let group = ec::EcGroup::from_curve_name(nid::Nid::X9_62_PRIME256V1).map_err(U2fError::OpenSSLError)?;
+    let key = ec::EcKey::generate(&group).map_err(U2fError::OpenSSLError)?;
+
+    key.private_key().to_vec_padded(32).map_err(U2fError::OpenSSLError)
+}
+
+/// Uncompressed public point of a P-256 private key.
+pub(crate) fn p256_public_key(private_key: &[u8]) -> Result<Vec<u8>, U2fError> {
+    let key = p256_private_key(private_key)?;
+
+    let mut ctx = bn::BigNumContext::new().map_err(U2fError::OpenSSLError)?;
+    key.public_key()
+        .to_bytes(key.group(), ec::PointConversionForm::UNCOMPRESSED, &mut ctx)
+        .map_err(U2fError::OpenSSLError)
+}
+
+/// DER encoded ECDSA signature over the SHA-256 digest of `msg`.
+pub(crate) fn sign_p256(private_key: &[u8], msg: &[u8]) -> Result<Vec<u8>, U2fError> {
+    let key = p256_private_key(private_key)?;
+
+    let signature = openssl::ecdsa::EcdsaSig::sign(&openssl::sha::sha256(msg), &key).map_err(U2fError::OpenSSLError)?;
+    signature.to_der().map_err(U2fError::OpenSSLError)
+}
+
+fn p256_private_key(private_key: &[u8]) -> Result<ec::EcKey<Private>, U2fError> {
+    if private_key.len() != 32 {
+        return Err(U2fError::InvalidPrivateKey);
+    }
+
+    let group = ec::EcGroup::from_curve_name(nid::Nid::X9_62_PRIME256V1).map_err(U2fError::OpenSSLError)?;
+    let private_key = bn::BigNum::from_slice(private_key).map_err(U2fError::OpenSSLError)?;
+
+    let mut ctx = bn::BigNumContext::new().map_err(U2fError::OpenSSLError)?;
+    let mut public_key = ec::EcPoint::new(&group).map_err(U2fError::OpenSSLError)?;
+    public_key.mul_generator2(&group, &private_key, &mut ctx).map_err(U2fError::OpenSSLError)?;
+
+    let key = ec::EcKey::from_private_components(&group, &private_key, &public_key).map_err(|_e| U2fError::InvalidPrivateKey)?;
+    key.check_key().map_err(|_e| U2fError::InvalidPrivateKey)?;
+    Ok(key)
+}
+
+/// AES-256-GCM, returning the ciphertext followed by the tag.
+pub(crate) fn seal_aes_256_gcm(key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, U2fError> {
+    let mut tag = [0u8; 16];
+    let mut sealed = symm::encrypt_aead(symm::Cipher::aes_256_gcm(), key, Some(nonce), aad, plaintext, &mut tag)
+        .map_err(|_e| U2fError::EncryptionError)?;
+
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+pub(crate) fn open_aes_256_gcm(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, U2fError> {
+    if sealed.len() < 16 {
+        return Err(U2fError::EncryptionError);
+    }
+
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+    symm::decrypt_aead(symm::Cipher::aes_256_gcm(), key, Some(nonce), aad, ciphertext, tag)
+        .map_err(|_e| U2fError::EncryptionError)
+}
+
 pub struct NISTP256Key {
     /// The key's public X coordinate.
     pub x: [u8; 32],
@@ -156,20 +251,20 @@ impl NISTP256Key {
 
     fn get_key(&self) -> Result<ec::EcKey<Public>, U2fError> {
         let ec_group = ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1)
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
 
         let xbn =
-            bn::BigNum::from_slice(&self.x).map_err(|e| U2fError::OpenSSLError(e))?;
+            bn::BigNum::from_slice(&self.x).map_err(U2fError::OpenSSLError)?;
         let ybn =
-            bn::BigNum::from_slice(&self.y).map_err(|e| U2fError::OpenSSLError(e))?;
+            bn::BigNum::from_slice(&self.y).map_err(U2fError::OpenSSLError)?;
 
         let ec_key = openssl::ec::EcKey::from_public_key_affine_coordinates(&ec_group, &xbn, &ybn)
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
 
         // Validate the key is sound. IIRC this actually checks the values
         // are correctly on the curve as specified
         ec_key.check_key()
-            .map_err(|e| U2fError::OpenSSLError(e))?;
+            .map_err(U2fError::OpenSSLError)?;
 
         Ok(ec_key)
     }
@@ -180,10 +275,10 @@ impl NISTP256Key {
     {
         let pkey = self.get_key()?;
 
-        let signature = openssl::ecdsa::EcdsaSig::from_der(signature).map_err(|e| U2fError::OpenSSLError(e))?;
-        let hash = openssl::sha::sha256(&verification_data);
+        let signature = openssl::ecdsa::EcdsaSig::from_der(signature).map_err(U2fError::OpenSSLError)?;
+        let hash = openssl::sha::sha256(verification_data);
 
-        signature.verify(hash.as_ref(), &pkey).map_err(|e| U2fError::OpenSSLError(e))
+        signature.verify(hash.as_ref(), &pkey).map_err(U2fError::OpenSSLError)
     }
 }
 
@@ -0,0 +1,348 @@
+// Cryptographic primitives behind registration, authentication, attestation, metadata and
+// the soft token. OpenSSL is the default backend; the `rust-crypto` feature adds a pure-Rust
+// one built on the RustCrypto crates, which is the default only when OpenSSL is left out.
+
+#[cfg(feature = "openssl")]
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+#[cfg(feature = "openssl")]
+use crate::crypto::{self, NISTP256Key, X509PublicKey};
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub trait CryptoProvider: Send + Sync {
+    fn sha256(&self, data: &[u8]) -> [u8; 32];
+
+    // Only used for the key identifiers of the metadata service.
+    fn sha1(&self, data: &[u8]) -> [u8; 20];
+
+    // Fills `buf` from a cryptographically secure source.
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<()>;
+
+    // Verifies a DER encoded ECDSA signature over the SHA-256 digest of `msg`. `public_key`
+    // is an uncompressed P-256 point; points off the curve are `InvalidPublicKey` and
+    // malformed signatures `BadSignature`.
+    fn verify_p256(&self, public_key: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool>;
+
+    // A new P-256 private key, as a 32 byte big-endian scalar.
+    fn generate_p256_key(&self) -> Result<Vec<u8>>;
+
+    // Uncompressed public point of a P-256 private key.
+    fn p256_public_key(&self, private_key: &[u8]) -> Result<Vec<u8>>;
+
+    // DER encoded ECDSA signature over the SHA-256 digest of `msg`.
+    fn sign_p256(&self, private_key: &[u8], msg: &[u8]) -> Result<Vec<u8>>;
+
+    // Parses a DER encoded X.509 certificate.
+    fn parse_certificate(&self, der: &[u8]) -> Result<Certificate>;
+
+    // Whether the key of `issuer` signed `cert`, both DER encoded.
+    fn verify_certificate(&self, cert: &[u8], issuer: &[u8]) -> Result<bool>;
+
+    // Verifies a signature over the SHA-256 digest of `msg` made with the subject key of the
+    // DER encoded `cert`: DER encoded ECDSA for EC keys, PKCS#1 v1.5 for RSA keys.
+    fn verify_signature(&self, cert: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool>;
+
+    // AES-256-GCM with a 12 byte nonce, returning the ciphertext followed by the 16 byte tag.
+    fn seal_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    // Reverses `seal_aes_256_gcm`, failing with `EncryptionError` when the tag does not match.
+    fn open_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>>;
+}
+
+// The parts of a certificate the ceremony needs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    // Common names of the subject and issuer.
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    // Uncompressed subject public key, when it is a P-256 key.
+    pub p256_public_key: Option<Vec<u8>>,
+    // Subject public key bits: the point of EC keys, the PKCS#1 RSAPublicKey of RSA keys.
+    pub public_key: Vec<u8>,
+}
+
+// OpenSSL when the `openssl` feature is on, RustCrypto otherwise. Cargo unifies features
+// across a build, so a dependency enabling `rust-crypto` must not switch the backend of
+// everyone else; pick `RustCryptoProvider` explicitly with `U2f::with_crypto_provider`.
+pub fn default_provider() -> Arc<dyn CryptoProvider> {
+    #[cfg(feature = "openssl")]
+    return Arc::new(OpenSslProvider);
+
+    #[cfg(not(feature = "openssl"))]
+    return Arc::new(RustCryptoProvider);
+}
+
+#[cfg(feature = "openssl")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenSslProvider;
+
+#[cfg(feature = "openssl")]
+impl CryptoProvider for OpenSslProvider {
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        openssl::sha::sha256(data)
+    }
+
+    fn sha1(&self, data: &[u8]) -> [u8; 20] {
+        openssl::sha::sha1(data)
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        openssl::rand::rand_bytes(buf).map_err(|_e| U2fError::RandomSecureBytesError)
+    }
+
+    fn verify_p256(&self, public_key: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool> {
+        let public_key = NISTP256Key::from_bytes(public_key)?;
+        if openssl::ecdsa::EcdsaSig::from_der(signature).is_err() {
+            return Err(U2fError::BadSignature);
+        }
+
+        public_key.verify_signature(signature, msg).map_err(|e| match e {
+            U2fError::OpenSSLError(_) => U2fError::InvalidPublicKey,
+            e => e,
+        })
+    }
+
+    fn generate_p256_key(&self) -> Result<Vec<u8>> {
+        crypto::generate_p256_key()
+    }
+
+    fn p256_public_key(&self, private_key: &[u8]) -> Result<Vec<u8>> {
+        crypto::p256_public_key(private_key)
+    }
+
+    fn sign_p256(&self, private_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+        crypto::sign_p256(private_key, msg)
+    }
+
+    fn parse_certificate(&self, der: &[u8]) -> Result<Certificate> {
+        let cert = X509PublicKey::try_from(der)?;
+
+        Ok(Certificate {
+            subject: cert.subject_name(),
+            issuer: cert.issuer_name(),
+            p256_public_key: cert.p256_public_key()?,
+            public_key: cert.public_key_bits()?,
+        })
+    }
+
+    fn verify_certificate(&self, cert: &[u8], issuer: &[u8]) -> Result<bool> {
+        let cert = X509PublicKey::try_from(cert)?;
+        let issuer = X509PublicKey::try_from(issuer)?;
+
+        cert.is_signed_by(&issuer)
+    }
+
+    fn verify_signature(&self, cert: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool> {
+        X509PublicKey::try_from(cert)?.verify_signature(signature, msg)
+    }
+
+    fn seal_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        crypto::seal_aes_256_gcm(key, nonce, aad, plaintext)
+    }
+
+    fn open_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        crypto::open_aes_256_gcm(key, nonce, aad, sealed)
+    }
+}
+
+#[cfg(feature = "rust-crypto")]
+pub use self::rust_crypto::RustCryptoProvider;
+
+#[cfg(feature = "rust-crypto")]
+mod rust_crypto {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use aes_gcm::{Aes256Gcm, Nonce};
+    use p256::ecdsa::signature::{Signer, Verifier};
+    use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+    use rsa::pkcs1::DecodeRsaPublicKey;
+    use rsa::{Pkcs1v15Sign, RsaPublicKey};
+    use sha1::Sha1;
+    use sha2::{Digest, Sha256};
+    use x509_cert::der::asn1::ObjectIdentifier;
+    use x509_cert::der::Decode;
+    use x509_cert::name::Name;
+    use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+
+    use super::{Certificate, CryptoProvider, Result};
+    use crate::der;
+    use crate::u2ferror::U2fError;
+
+    const COMMON_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.4.3");
+    const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+    const PRIME256V1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+    const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+    const ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+    const SHA256_WITH_RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+
+    // Pure-Rust backend. Keys are P-256 or RSA; certificate signatures can only be checked
+    // for ecdsa-with-SHA256 and sha256WithRSAEncryption, others are `UnsupportedAlgorithm`.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct RustCryptoProvider;
+
+    impl CryptoProvider for RustCryptoProvider {
+        fn sha256(&self, data: &[u8]) -> [u8; 32] {
+            Sha256::digest(data).into()
+        }
+
+        fn sha1(&self, data: &[u8]) -> [u8; 20] {
+            Sha1::digest(data).into()
+        }
+
+        fn random_bytes(&self, buf: &mut [u8]) -> Result<()> {
+            getrandom::getrandom(buf).map_err(|_e| U2fError::RandomSecureBytesError)
+        }
+
+        fn verify_p256(&self, public_key: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool> {
+            if public_key.len() != 65 || public_key[0] != 0x04 {
+                return Err(U2fError::InvalidPublicKey);
+            }
+
+            let public_key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_e| U2fError::InvalidPublicKey)?;
+            let signature = Signature::from_der(signature).map_err(|_e| U2fError::BadSignature)?;
+
+            Ok(public_key.verify(msg, &signature).is_ok())
+        }
+
+        fn generate_p256_key(&self) -> Result<Vec<u8>> {
+            // Out of range scalars are astronomically rare; draw again if one comes up.
+            loop {
+                let mut private_key = [0u8; 32];
+                self.random_bytes(&mut private_key)?;
+                if SigningKey::from_slice(&private_key).is_ok() {
+                    return Ok(private_key.to_vec());
+                }
+            }
+        }
+
+        fn p256_public_key(&self, private_key: &[u8]) -> Result<Vec<u8>> {
+            let key = SigningKey::from_slice(private_key).map_err(|_e| U2fError::InvalidPrivateKey)?;
+            Ok(key.verifying_key().to_encoded_point(false).as_bytes().to_vec())
+        }
+
+        fn sign_p256(&self, private_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+            let key = SigningKey::from_slice(private_key).map_err(|_e| U2fError::InvalidPrivateKey)?;
+            let signature: Signature = key.sign(msg);
+            Ok(signature.to_der().as_bytes().to_vec())
+        }
+
+        fn parse_certificate(&self, der: &[u8]) -> Result<Certificate> {
+            let cert = CertificateParts::split(der)?;
+
+            Ok(Certificate {
+                subject: common_name(&cert.subject),
+                issuer: common_name(&cert.issuer),
+                p256_public_key: p256_public_key(&cert.public_key),
+                public_key: cert.public_key.subject_public_key.raw_bytes().to_vec(),
+            })
+        }
+
+        fn verify_certificate(&self, cert: &[u8], issuer: &[u8]) -> Result<bool> {
+            let cert = CertificateParts::split(cert)?;
+            let issuer = CertificateParts::split(issuer)?;
+
+            let key_algorithm = match cert.signature_algorithm.oid {
+                ECDSA_WITH_SHA256 => EC_PUBLIC_KEY,
+                SHA256_WITH_RSA_ENCRYPTION => RSA_ENCRYPTION,
+                _ => return Err(U2fError::UnsupportedAlgorithm),
+            };
+            if issuer.public_key.algorithm.oid != key_algorithm {
+                return Err(U2fError::UnsupportedAlgorithm);
+            }
+
+            verify_with_key(&issuer.public_key, cert.tbs, cert.signature)
+        }
+
+        fn verify_signature(&self, cert: &[u8], msg: &[u8], signature: &[u8]) -> Result<bool> {
+            let cert = CertificateParts::split(cert)?;
+            verify_with_key(&cert.public_key, msg, signature)
+        }
+
+        fn seal_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+            aes_256_gcm(key, nonce)?
+                .encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad })
+                .map_err(|_e| U2fError::EncryptionError)
+        }
+
+        fn open_aes_256_gcm(&self, key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+            aes_256_gcm(key, nonce)?
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+                .map_err(|_e| U2fError::EncryptionError)
+        }
+    }
+
+    // `Nonce::from_slice` panics on any other length than 12.
+    fn aes_256_gcm(key: &[u8], nonce: &[u8]) -> Result<Aes256Gcm> {
+        if nonce.len() != 12 {
+            return Err(U2fError::EncryptionError);
+        }
+
+        Aes256Gcm::new_from_slice(key).map_err(|_e| U2fError::EncryptionError)
+    }
+
+    fn verify_with_key(public_key: &SubjectPublicKeyInfoOwned, msg: &[u8], signature: &[u8]) -> Result<bool> {
+        if let Some(public_key) = p256_public_key(public_key) {
+            return match RustCryptoProvider.verify_p256(&public_key, msg, signature) {
+                Err(U2fError::BadSignature) => Ok(false),
+                verified => verified,
+            };
+        }
+
+        if public_key.algorithm.oid != RSA_ENCRYPTION {
+            return Err(U2fError::UnsupportedAlgorithm);
+        }
+
+        let public_key = RsaPublicKey::from_pkcs1_der(public_key.subject_public_key.raw_bytes()).map_err(|_e| U2fError::InvalidPublicKey)?;
+        Ok(public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(msg), signature).is_ok())
+    }
+
+    // A certificate split with the crate's DER reader rather than decoded as a whole: some
+    // tokens ship attestation certificates with malformed validity times, which OpenSSL
+    // tolerates but a strict X.509 decoder rejects.
+    struct CertificateParts<'a> {
+        // The signed TBSCertificate, tag and length included.
+        tbs: &'a [u8],
+        issuer: Name,
+        subject: Name,
+        public_key: SubjectPublicKeyInfoOwned,
+        signature_algorithm: AlgorithmIdentifierOwned,
+        signature: &'a [u8],
+    }
+
+    impl<'a> CertificateParts<'a> {
+        fn split(cert: &'a [u8]) -> Result<Self> {
+            let fields = der::certificate_fields(cert).map_err(|_e| U2fError::BadCertificate)?;
+
+            Ok(CertificateParts {
+                tbs: fields.tbs,
+                issuer: Name::from_der(fields.issuer).map_err(|_e| U2fError::BadCertificate)?,
+                subject: Name::from_der(fields.subject).map_err(|_e| U2fError::BadCertificate)?,
+                public_key: SubjectPublicKeyInfoOwned::from_der(fields.public_key_info).map_err(|_e| U2fError::BadCertificate)?,
+                signature_algorithm: AlgorithmIdentifierOwned::from_der(fields.signature_algorithm).map_err(|_e| U2fError::BadCertificate)?,
+                signature: fields.signature,
+            })
+        }
+    }
+
+    fn common_name(name: &Name) -> Option<String> {
+        name.0
+            .iter()
+            .flat_map(|rdn| rdn.0.iter())
+            .find(|attribute| attribute.oid == COMMON_NAME)
+            .and_then(|attribute| std::str::from_utf8(attribute.value.value()).ok())
+            .map(|s| s.to_string())
+    }
+
+    fn p256_public_key(spki: &SubjectPublicKeyInfoOwned) -> Option<Vec<u8>> {
+        let curve = spki.algorithm.parameters.as_ref()?.decode_as::<ObjectIdentifier>().ok()?;
+        if spki.algorithm.oid != EC_PUBLIC_KEY || curve != PRIME256V1 {
+            return None;
+        }
+
+        let public_key = VerifyingKey::from_sec1_bytes(spki.subject_public_key.as_bytes()?).ok()?;
+        Some(public_key.to_encoded_point(false).as_bytes().to_vec())
+    }
+}
@@ -0,0 +1,243 @@
+// Minimal DER reader and writer, enough to take X.509 certificates apart and to build the
+// simple ones of the soft token.
+// http://en.wikipedia.org/wiki/X.690
+
+use chrono::prelude::*;
+
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const BIT_STRING: u8 = 0x03;
+pub const BOOLEAN: u8 = 0x01;
+pub const OID: u8 = 0x06;
+pub const UTF8_STRING: u8 = 0x0c;
+pub const UTC_TIME: u8 = 0x17;
+pub const GENERALIZED_TIME: u8 = 0x18;
+// [0] EXPLICIT, the version field of a TBSCertificate.
+pub const VERSION: u8 = 0xa0;
+// [3] EXPLICIT, the extensions field of a TBSCertificate.
+pub const EXTENSIONS: u8 = 0xa3;
+
+// The fields of a certificate, split but not interpreted. Unless noted otherwise each one
+// keeps its tag and length.
+pub struct CertificateFields<'a> {
+    // The signed TBSCertificate.
+    pub tbs: &'a [u8],
+    // Content of the serial number INTEGER.
+    pub serial_number: &'a [u8],
+    pub issuer: &'a [u8],
+    // Content of the validity SEQUENCE: two times.
+    pub validity: &'a [u8],
+    pub subject: &'a [u8],
+    pub public_key_info: &'a [u8],
+    pub signature_algorithm: &'a [u8],
+    // Signature bits, without the unused bits byte of the BIT STRING.
+    pub signature: &'a [u8],
+}
+
+// Splits the first TLV off `input`, returning its tag, its content and the remaining bytes.
+pub fn read_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    if input.len() < 2 {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    let tag = input[0];
+    let (len, header) = match input[1] {
+        len if len & 0x80 == 0 => (len as usize, 2),
+        len => {
+            let number_of_bytes = (len & 0x7f) as usize;
+            if number_of_bytes == 0 || number_of_bytes > std::mem::size_of::<usize>() || input.len() < 2 + number_of_bytes {
+                return Err(U2fError::Asm1DecoderError);
+            }
+
+            let mut length: usize = 0;
+            for byte in &input[2..2 + number_of_bytes] {
+                length = length.checked_mul(0x100).ok_or(U2fError::Asm1DecoderError)? + *byte as usize;
+            }
+            (length, 2 + number_of_bytes)
+        }
+    };
+
+    let end = header.checked_add(len).ok_or(U2fError::Asm1DecoderError)?;
+    if input.len() < end {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    Ok((tag, &input[header..end], &input[end..]))
+}
+
+// Reads a TLV and checks its tag.
+pub fn expect(input: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    let (found, content, rest) = read_tlv(input)?;
+    if found != tag {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    Ok((content, rest))
+}
+
+// Decodes the content of an OBJECT IDENTIFIER into its dotted form.
+pub fn oid_to_string(content: &[u8]) -> Result<String> {
+    if content.is_empty() {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    let mut arcs: Vec<u64> = vec![];
+    let mut value: u64 = 0;
+    for (i, byte) in content.iter().enumerate() {
+        value = value.checked_mul(0x80).ok_or(U2fError::Asm1DecoderError)? | (byte & 0x7f) as u64;
+        if byte & 0x80 != 0 {
+            if i == content.len() - 1 {
+                return Err(U2fError::Asm1DecoderError);
+            }
+            continue;
+        }
+
+        if arcs.is_empty() {
+            let first = std::cmp::min(value / 40, 2);
+            arcs.push(first);
+            arcs.push(value - first * 40);
+        } else {
+            arcs.push(value);
+        }
+        value = 0;
+    }
+
+    Ok(arcs.iter().map(|arc| arc.to_string()).collect::<Vec<String>>().join("."))
+}
+
+// Splits a DER encoded certificate into its fields.
+pub fn certificate_fields(cert: &[u8]) -> Result<CertificateFields<'_>> {
+    let (certificate, _) = expect(cert, SEQUENCE)?;
+    let (tbs, rest) = next_tlv(certificate)?;
+    let (signature_algorithm, rest) = next_tlv(rest)?;
+    let signature = match expect(rest, BIT_STRING)? {
+        ([0, signature @ ..], _) => signature,
+        _ => return Err(U2fError::Asm1DecoderError),
+    };
+
+    let (mut fields, _) = expect(tbs, SEQUENCE)?;
+    if fields.first() == Some(&VERSION) {
+        fields = next_tlv(fields)?.1;
+    }
+    let (serial_number, fields) = expect(fields, INTEGER)?;
+    // The signature algorithm, repeated.
+    let fields = next_tlv(fields)?.1;
+    let (issuer, fields) = next_tlv(fields)?;
+    let (validity, fields) = expect(fields, SEQUENCE)?;
+    let (subject, fields) = next_tlv(fields)?;
+    let (public_key_info, _) = next_tlv(fields)?;
+
+    Ok(CertificateFields { tbs, serial_number, issuer, validity, subject, public_key_info, signature_algorithm, signature })
+}
+
+// Splits the first TLV, header included, off `input`.
+pub fn next_tlv(input: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (_, _, rest) = read_tlv(input)?;
+    Ok(input.split_at(input.len() - rest.len()))
+}
+
+// Decodes the content of a UTCTime or GeneralizedTime in UTC. Seconds may be left out, as
+// some tokens do; anything else that is not well formed is `None`.
+pub fn parse_time(tag: u8, content: &[u8]) -> Option<DateTime<Utc>> {
+    let text = std::str::from_utf8(content).ok()?.strip_suffix('Z')?;
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let year: i32 = text.get(..2)?.parse().ok()?;
+            (if year >= 50 { 1900 + year } else { 2000 + year }, &text[2..])
+        }
+        GENERALIZED_TIME => (text.get(..4)?.parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+
+    let field = |i: usize| rest.get(2 * i..2 * i + 2).and_then(|field| field.parse::<u32>().ok());
+    let second = match rest.len() {
+        8 => 0,
+        10 => field(4)?,
+        _ => return None,
+    };
+
+    let date = NaiveDate::from_ymd_opt(year, field(0)?, field(1)?)?;
+    let time = date.and_hms_opt(field(2)?, field(3)?, second)?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+// Encodes a TLV.
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+
+    let len = content.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|b| *b == 0).collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend_from_slice(&bytes);
+    }
+
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+pub fn sequence(fields: &[&[u8]]) -> Vec<u8> {
+    tlv(SEQUENCE, &fields.concat())
+}
+
+// Encodes big-endian unsigned `bytes` as a (positive) INTEGER.
+pub fn integer(bytes: &[u8]) -> Vec<u8> {
+    let bytes: Vec<u8> = bytes.iter().copied().skip_while(|b| *b == 0).collect();
+
+    match bytes.first() {
+        None => tlv(INTEGER, &[0]),
+        Some(first) if first & 0x80 != 0 => tlv(INTEGER, &[&[0], &bytes[..]].concat()),
+        Some(_) => tlv(INTEGER, &bytes),
+    }
+}
+
+// Extensions of a DER encoded certificate as (OID, critical, extnValue content) tuples.
+pub fn certificate_extensions(cert: &[u8]) -> Result<Vec<(String, bool, &[u8])>> {
+    let (certificate, _) = expect(cert, SEQUENCE)?;
+    let (mut tbs, _) = expect(certificate, SEQUENCE)?;
+
+    let mut extensions = None;
+    while !tbs.is_empty() {
+        let (tag, content, rest) = read_tlv(tbs)?;
+        if tag == EXTENSIONS {
+            extensions = Some(content);
+        }
+        tbs = rest;
+    }
+
+    let mut result = vec![];
+    let mut list = match extensions {
+        Some(explicit) => expect(explicit, SEQUENCE)?.0,
+        None => return Ok(result),
+    };
+
+    while !list.is_empty() {
+        let (extension, rest) = expect(list, SEQUENCE)?;
+        list = rest;
+
+        let (oid, mut fields) = expect(extension, OID)?;
+        let mut critical = false;
+        if let Ok((flag, rest)) = expect(fields, BOOLEAN) {
+            critical = flag.first().is_some_and(|b| *b != 0);
+            fields = rest;
+        }
+        let (value, _) = expect(fields, OCTET_STRING)?;
+
+        result.push((oid_to_string(oid)?, critical, value));
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+
+use chrono::prelude::*;
+
+use crate::crypto_provider::default_provider;
+use crate::der;
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+// FIDO U2F authenticator transports extension.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-authenticator-transports-extension-v1.2-ps-20170411.html
+pub const TRANSPORTS_OID: &str = "1.3.6.1.4.1.45724.2.1.1";
+// FIDO AAGUID extension.
+pub const AAGUID_OID: &str = "1.3.6.1.4.1.45724.1.1.4";
+// Yubico device identifier, carried either as the extension OID itself or as the
+// dotted string value of the 1.3.6.1.4.1.41482.2 extension.
+pub const YUBICO_DEVICE_OID_PREFIX: &str = "1.3.6.1.4.1.41482.1.";
+pub const YUBICO_DEVICE_ID_OID: &str = "1.3.6.1.4.1.41482.2";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Transport {
+    BluetoothClassic,
+    BluetoothLowEnergy,
+    Usb,
+    Nfc,
+    UsbInternal,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transport::BluetoothClassic => write!(f, "Bluetooth"),
+            Transport::BluetoothLowEnergy => write!(f, "BLE"),
+            Transport::Usb => write!(f, "USB"),
+            Transport::Nfc => write!(f, "NFC"),
+            Transport::UsbInternal => write!(f, "USB internal"),
+        }
+    }
+}
+
+// Device details decoded from an attestation certificate.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub serial_number: Option<String>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub signature_algorithm: Option<String>,
+    pub transports: Vec<Transport>,
+    pub aaguid: Option<String>,
+    pub yubico_device_id: Option<String>,
+}
+
+impl DeviceInfo {
+    // Decodes a DER encoded attestation certificate.
+    pub fn from_certificate(cert: &[u8]) -> Result<DeviceInfo> {
+        let names = default_provider().parse_certificate(cert)?;
+        let fields = der::certificate_fields(cert).map_err(|_e| U2fError::BadCertificate)?;
+        let (not_before, not_after) = validity(fields.validity);
+
+        let mut info = DeviceInfo {
+            subject: names.subject,
+            issuer: names.issuer,
+            serial_number: Some(serial_number(fields.serial_number)),
+            not_before,
+            not_after,
+            signature_algorithm: signature_algorithm(fields.signature_algorithm),
+            transports: vec![],
+            aaguid: None,
+            yubico_device_id: None,
+        };
+
+        for (oid, _critical, value) in der::certificate_extensions(cert)? {
+            if oid == TRANSPORTS_OID {
+                info.transports = parse_transports(value)?;
+            } else if oid == AAGUID_OID {
+                info.aaguid = Some(parse_aaguid(value)?);
+            } else if oid == YUBICO_DEVICE_ID_OID {
+                info.yubico_device_id = std::str::from_utf8(value).ok().map(|s| s.to_string());
+            } else if oid.starts_with(YUBICO_DEVICE_OID_PREFIX) && info.yubico_device_id.is_none() {
+                info.yubico_device_id = Some(oid);
+            }
+        }
+
+        Ok(info)
+    }
+
+    // Product name for known Yubico device identifiers.
+    pub fn device_name(&self) -> Option<&'static str> {
+        let id = self.yubico_device_id.as_ref()?;
+
+        match &id[..] {
+            "1.3.6.1.4.1.41482.1.1" => Some("Security Key by Yubico"),
+            "1.3.6.1.4.1.41482.1.2" => Some("YubiKey NEO"),
+            "1.3.6.1.4.1.41482.1.3" => Some("YubiKey Plus"),
+            "1.3.6.1.4.1.41482.1.4" => Some("YubiKey Edge"),
+            "1.3.6.1.4.1.41482.1.5" => Some("YubiKey 4"),
+            _ => None,
+        }
+    }
+}
+
+// Product name (or subject CN) followed by the supported transports, e.g. "YubiKey 4 (USB, NFC)".
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self
+            .device_name()
+            .map(|name| name.to_string())
+            .or_else(|| self.subject.clone())
+            .unwrap_or_else(|| "Unknown device".to_string());
+        write!(f, "{}", name)?;
+
+        if !self.transports.is_empty() {
+            let transports: Vec<String> = self.transports.iter().map(|t| t.to_string()).collect();
+            write!(f, " ({})", transports.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Upper case hex, like OpenSSL prints serial numbers: whole bytes, leading zero bytes dropped.
+fn serial_number(serial_number: &[u8]) -> String {
+    let bytes: Vec<u8> = serial_number.iter().copied().skip_while(|b| *b == 0).collect();
+
+    if bytes.is_empty() {
+        "0".to_string()
+    } else {
+        hex::encode_upper(bytes)
+    }
+}
+
+fn validity(validity: &[u8]) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let (not_before, rest) = match der::read_tlv(validity) {
+        Ok((tag, content, rest)) => (der::parse_time(tag, content), rest),
+        Err(_) => return (None, None),
+    };
+    let not_after = der::read_tlv(rest).ok().and_then(|(tag, content, _)| der::parse_time(tag, content));
+
+    (not_before, not_after)
+}
+
+// Name of the certificate signature algorithm, as OpenSSL spells it (e.g. `ecdsa-with-SHA256`),
+// or its dotted OID when unknown.
+fn signature_algorithm(algorithm: &[u8]) -> Option<String> {
+    let (fields, _) = der::expect(algorithm, der::SEQUENCE).ok()?;
+    let (oid, _) = der::expect(fields, der::OID).ok()?;
+    let oid = der::oid_to_string(oid).ok()?;
+
+    let name = match &oid[..] {
+        "1.2.840.113549.1.1.5" => "sha1WithRSAEncryption",
+        "1.2.840.113549.1.1.11" => "sha256WithRSAEncryption",
+        "1.2.840.113549.1.1.12" => "sha384WithRSAEncryption",
+        "1.2.840.113549.1.1.13" => "sha512WithRSAEncryption",
+        "1.2.840.10045.4.1" => "ecdsa-with-SHA1",
+        "1.2.840.10045.4.3.2" => "ecdsa-with-SHA256",
+        "1.2.840.10045.4.3.3" => "ecdsa-with-SHA384",
+        "1.2.840.10045.4.3.4" => "ecdsa-with-SHA512",
+        "1.3.101.112" => "ED25519",
+        _ => return Some(oid),
+    };
+
+    Some(name.to_string())
+}
+
+// The extension value is a BIT STRING, bit 0 being the most significant bit of the first byte.
+fn parse_transports(value: &[u8]) -> Result<Vec<Transport>> {
+    let (bits, _) = der::expect(value, der::BIT_STRING)?;
+    let flags = match bits {
+        [_unused, first, ..] => *first,
+        _ => return Ok(vec![]),
+    };
+
+    let all = [
+        Transport::BluetoothClassic,
+        Transport::BluetoothLowEnergy,
+        Transport::Usb,
+        Transport::Nfc,
+        Transport::UsbInternal,
+    ];
+
+    Ok(all
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| flags & (0x80 >> bit) != 0)
+        .map(|(_, transport)| *transport)
+        .collect())
+}
+
+// The extension value is an OCTET STRING holding the 16 byte AAGUID.
+fn parse_aaguid(value: &[u8]) -> Result<String> {
+    let (aaguid, _) = der::expect(value, der::OCTET_STRING)?;
+    if aaguid.len() != 16 {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    let hex = hex::encode(aaguid);
+    Ok(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]))
+}
@@ -0,0 +1,45 @@
+// Entry points for the cargo-fuzz targets in `fuzz/`. Each function feeds
+// attacker-controlled bytes to one decoder and ignores the outcome: the only
+// failure a fuzzer is looking for is a panic.
+
+use bytes::Bytes;
+
+use crate::authorization::parse_sign_response;
+use crate::crypto_provider::default_provider;
+use crate::messages::*;
+use crate::protocol::Challenge;
+use crate::register::parse_registration;
+use crate::util;
+
+const APP_ID: &str = "https://u2f.bin.coffee";
+
+const REGISTER_CLIENT_DATA: &str = r#"{"challenge":"x2ihLZaIcGhA-ByY2mgLc8aofEM","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+
+const SIGN_CHALLENGE: &str = "bRLh0fxu3DvMr5ws2ylmnQ";
+const SIGN_CLIENT_DATA: &str = r#"{"challenge":"bRLh0fxu3DvMr5ws2ylmnQ","origin":"https://u2f.bin.coffee","typ":"navigator.id.getAssertion"}"#;
+const SIGN_PUBLIC_KEY: &str = "BL76bCsU_MWyiJlgOoX-WvsxFrKtDx3mq8jekbg_U1vDOxzWnsTDsBnd88YyJFPKnrGU5UxBhNR970LSAtiNCFo";
+
+pub fn registration(data: &[u8]) {
+    let _ = parse_registration(APP_ID.to_string(), REGISTER_CLIENT_DATA.as_bytes().to_vec(), data.to_vec());
+}
+
+pub fn sign_response(data: &[u8]) {
+    let public_key = base64::decode_config(SIGN_PUBLIC_KEY, base64::URL_SAFE_NO_PAD).unwrap();
+    let _ = parse_sign_response(APP_ID.to_string(), SIGN_CHALLENGE.to_string(), SIGN_CLIENT_DATA.as_bytes().to_vec(), public_key, data.to_vec());
+}
+
+pub fn asn_length(data: &[u8]) {
+    let _ = util::asn_length(Bytes::from(data));
+}
+
+pub fn public_key(data: &[u8]) {
+    let _ = default_provider().verify_p256(data, b"", &[0x30, 0x00]);
+}
+
+pub fn messages(data: &[u8]) {
+    let _ = ClientData::from_bytes(data);
+    let _ = serde_json::from_slice::<RegisterRequest>(data);
+    let _ = serde_json::from_slice::<RegisterResponse>(data);
+    let _ = serde_json::from_slice::<SignResponse>(data);
+    let _ = serde_json::from_slice::<Challenge>(data);
+}
@@ -0,0 +1,292 @@
+// U2FHID framing: messages split into 64 byte HID reports. No I/O is done here.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-hid-protocol-v1.2-ps-20170411.html
+
+use crate::raw::{ApduEncoding, Request, Response};
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub const HID_RPT_SIZE: usize = 64;
+// Payload bytes of an initialization and a continuation packet.
+pub const INIT_DATA_SIZE: usize = HID_RPT_SIZE - 7;
+pub const CONT_DATA_SIZE: usize = HID_RPT_SIZE - 5;
+pub const MAX_SEQ: u8 = 0x7f;
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_SIZE + (MAX_SEQ as usize + 1) * CONT_DATA_SIZE;
+
+pub const CID_BROADCAST: u32 = 0xffff_ffff;
+pub const TYPE_INIT: u8 = 0x80;
+
+pub const U2FHID_PING: u8 = TYPE_INIT | 0x01;
+pub const U2FHID_MSG: u8 = TYPE_INIT | 0x03;
+pub const U2FHID_LOCK: u8 = TYPE_INIT | 0x04;
+pub const U2FHID_INIT: u8 = TYPE_INIT | 0x06;
+pub const U2FHID_WINK: u8 = TYPE_INIT | 0x08;
+pub const U2FHID_SYNC: u8 = TYPE_INIT | 0x3c;
+pub const U2FHID_ERROR: u8 = TYPE_INIT | 0x3f;
+pub const U2FHID_VENDOR_FIRST: u8 = TYPE_INIT | 0x40;
+
+pub const U2FHID_IF_VERSION: u8 = 2;
+pub const INIT_NONCE_SIZE: usize = 8;
+
+pub const CAPFLAG_WINK: u8 = 0x01;
+pub const CAPFLAG_LOCK: u8 = 0x02;
+
+pub const ERR_NONE: u8 = 0x00;
+pub const ERR_INVALID_CMD: u8 = 0x01;
+pub const ERR_INVALID_PAR: u8 = 0x02;
+pub const ERR_INVALID_LEN: u8 = 0x03;
+pub const ERR_INVALID_SEQ: u8 = 0x04;
+pub const ERR_MSG_TIMEOUT: u8 = 0x05;
+pub const ERR_CHANNEL_BUSY: u8 = 0x06;
+pub const ERR_LOCK_REQUIRED: u8 = 0x0a;
+pub const ERR_SYNC_FAIL: u8 = 0x0b;
+pub const ERR_OTHER: u8 = 0x7f;
+
+pub type Packet = [u8; HID_RPT_SIZE];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Ping,
+    Msg,
+    Lock,
+    Init,
+    Wink,
+    Sync,
+    Error,
+    // Vendor specific commands, U2FHID_VENDOR_FIRST and up.
+    Vendor(u8),
+}
+
+impl Command {
+    pub fn from_byte(byte: u8) -> Result<Command> {
+        match byte {
+            U2FHID_PING => Ok(Command::Ping),
+            U2FHID_MSG => Ok(Command::Msg),
+            U2FHID_LOCK => Ok(Command::Lock),
+            U2FHID_INIT => Ok(Command::Init),
+            U2FHID_WINK => Ok(Command::Wink),
+            U2FHID_SYNC => Ok(Command::Sync),
+            U2FHID_ERROR => Ok(Command::Error),
+            byte if byte >= U2FHID_VENDOR_FIRST => Ok(Command::Vendor(byte)),
+            _ => Err(U2fError::HidError(ERR_INVALID_CMD)),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Command::Ping => U2FHID_PING,
+            Command::Msg => U2FHID_MSG,
+            Command::Lock => U2FHID_LOCK,
+            Command::Init => U2FHID_INIT,
+            Command::Wink => U2FHID_WINK,
+            Command::Sync => U2FHID_SYNC,
+            Command::Error => U2FHID_ERROR,
+            Command::Vendor(byte) => byte,
+        }
+    }
+}
+
+// A U2FHID request or response on a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub cid: u32,
+    pub command: Command,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(cid: u32, command: Command, data: Vec<u8>) -> Self {
+        Message { cid, command, data }
+    }
+
+    // Channel allocation request, sent on the broadcast channel.
+    pub fn init(nonce: [u8; INIT_NONCE_SIZE]) -> Self {
+        Message::new(CID_BROADCAST, Command::Init, nonce.to_vec())
+    }
+
+    pub fn ping(cid: u32, data: Vec<u8>) -> Self {
+        Message::new(cid, Command::Ping, data)
+    }
+
+    pub fn wink(cid: u32) -> Self {
+        Message::new(cid, Command::Wink, vec![])
+    }
+
+    // Locks the device to the channel for `seconds` (at most 10), zero releases the lock.
+    pub fn lock(cid: u32, seconds: u8) -> Self {
+        Message::new(cid, Command::Lock, vec![seconds])
+    }
+
+    pub fn sync(cid: u32, nonce: u8) -> Self {
+        Message::new(cid, Command::Sync, vec![nonce])
+    }
+
+    // U2FHID_MSG carrying the request as an extended length APDU.
+    pub fn apdu(cid: u32, request: &Request) -> Result<Self> {
+        Ok(Message::new(cid, Command::Msg, request.encode(ApduEncoding::Extended)?))
+    }
+
+    // The APDU response of a U2FHID_MSG exchange. U2FHID_ERROR replies become `HidError`.
+    pub fn into_response(self) -> Result<Response> {
+        match self.command {
+            Command::Msg => Response::decode(&self.data),
+            Command::Error => Err(U2fError::HidError(self.data.first().copied().unwrap_or(ERR_OTHER))),
+            _ => Err(U2fError::InvalidHidPacket),
+        }
+    }
+
+    // Splits the message into an initialization packet followed by continuation packets.
+    pub fn to_packets(&self) -> Result<Vec<Packet>> {
+        if self.data.len() > MAX_MESSAGE_SIZE {
+            return Err(U2fError::HidError(ERR_INVALID_LEN));
+        }
+
+        let cid = self.cid.to_be_bytes();
+        let length = (self.data.len() as u16).to_be_bytes();
+        let split = std::cmp::min(self.data.len(), INIT_DATA_SIZE);
+
+        let mut packet = [0u8; HID_RPT_SIZE];
+        packet[..4].copy_from_slice(&cid);
+        packet[4] = self.command.to_byte();
+        packet[5..7].copy_from_slice(&length);
+        packet[7..7 + split].copy_from_slice(&self.data[..split]);
+
+        let mut packets = vec![packet];
+        for (seq, chunk) in self.data[split..].chunks(CONT_DATA_SIZE).enumerate() {
+            let mut packet = [0u8; HID_RPT_SIZE];
+            packet[..4].copy_from_slice(&cid);
+            packet[4] = seq as u8;
+            packet[5..5 + chunk.len()].copy_from_slice(chunk);
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+}
+
+struct PartialMessage {
+    message: Message,
+    length: usize,
+    seq: u8,
+}
+
+// Reassembles messages from packets, checking continuation sequence numbers.
+#[derive(Default)]
+pub struct Decoder {
+    partial: Option<PartialMessage>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    // Feeds one packet, returning the message it completes. While a message is being
+    // reassembled, packets of other channels leave it untouched: their continuation packets
+    // are skipped, as several applications may share the device, and their initialization
+    // packets fail with ERR_CHANNEL_BUSY, as a device would answer them.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<Message>> {
+        if packet.len() != HID_RPT_SIZE {
+            return Err(U2fError::InvalidHidPacket);
+        }
+
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+
+        if packet[4] & TYPE_INIT != 0 {
+            match self.partial.as_ref() {
+                Some(partial) if partial.message.cid == cid => {
+                    self.partial = None;
+                    return Err(U2fError::HidError(ERR_INVALID_SEQ));
+                }
+                Some(_) => return Err(U2fError::HidError(ERR_CHANNEL_BUSY)),
+                None => (),
+            }
+
+            let command = Command::from_byte(packet[4])?;
+            let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+            if length > MAX_MESSAGE_SIZE {
+                return Err(U2fError::HidError(ERR_INVALID_LEN));
+            }
+
+            let split = std::cmp::min(length, INIT_DATA_SIZE);
+            let message = Message::new(cid, command, packet[7..7 + split].to_vec());
+            if length == split {
+                return Ok(Some(message));
+            }
+
+            self.partial = Some(PartialMessage { message, length, seq: 0 });
+            return Ok(None);
+        }
+
+        let partial = match self.partial.as_mut() {
+            Some(partial) if partial.message.cid == cid => partial,
+            Some(_) => return Ok(None),
+            None => return Err(U2fError::InvalidHidPacket),
+        };
+
+        if packet[4] != partial.seq {
+            self.partial = None;
+            return Err(U2fError::HidError(ERR_INVALID_SEQ));
+        }
+
+        let remaining = partial.length - partial.message.data.len();
+        let take = std::cmp::min(remaining, CONT_DATA_SIZE);
+        partial.message.data.extend_from_slice(&packet[5..5 + take]);
+        partial.seq += 1;
+
+        if take < remaining {
+            return Ok(None);
+        }
+
+        Ok(self.partial.take().map(|partial| partial.message))
+    }
+}
+
+// Reply to U2FHID_INIT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitResponse {
+    pub nonce: [u8; INIT_NONCE_SIZE],
+    // Channel allocated to the caller.
+    pub cid: u32,
+    pub protocol_version: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub build_version: u8,
+    pub capabilities: u8,
+}
+
+impl InitResponse {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.nonce.to_vec();
+        data.extend_from_slice(&self.cid.to_be_bytes());
+        data.extend_from_slice(&[self.protocol_version, self.major_version, self.minor_version, self.build_version, self.capabilities]);
+        data
+    }
+
+    // Decodes the reply to `Message::init(nonce)`, rejecting replies meant for another nonce.
+    pub fn decode(message: &Message, nonce: [u8; INIT_NONCE_SIZE]) -> Result<InitResponse> {
+        if message.command != Command::Init || message.data.len() < 17 || message.data[..INIT_NONCE_SIZE] != nonce {
+            return Err(U2fError::InvalidHidPacket);
+        }
+
+        let data = &message.data;
+        Ok(InitResponse {
+            nonce,
+            cid: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            protocol_version: data[12],
+            major_version: data[13],
+            minor_version: data[14],
+            build_version: data[15],
+            capabilities: data[16],
+        })
+    }
+
+    pub fn supports_wink(&self) -> bool {
+        self.capabilities & CAPFLAG_WINK != 0
+    }
+
+    pub fn supports_lock(&self) -> bool {
+        self.capabilities & CAPFLAG_LOCK != 0
+    }
+}
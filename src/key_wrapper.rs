@@ -0,0 +1,81 @@
+// Stateless key handles: the credential private key is sealed into the key handle with
+// AES-256-GCM, the application parameter (SHA-256 of the app ID) being the associated data.
+
+use std::sync::Arc;
+
+use crate::crypto_provider::{default_provider, CryptoProvider};
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub const WRAPPING_KEY_LENGTH: usize = 32;
+pub const PRIVATE_KEY_LENGTH: usize = 32;
+const KEY_HANDLE_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+pub const KEY_HANDLE_LENGTH: usize = 1 + NONCE_LENGTH + PRIVATE_KEY_LENGTH + TAG_LENGTH;
+
+// Wraps P-256 private keys (32 byte big-endian scalars) into key handles.
+#[derive(Clone)]
+pub struct KeyWrapper {
+    key: [u8; WRAPPING_KEY_LENGTH],
+    crypto: Arc<dyn CryptoProvider>,
+}
+
+impl KeyWrapper {
+    pub fn new(key: [u8; WRAPPING_KEY_LENGTH]) -> Self {
+        KeyWrapper { key, crypto: default_provider() }
+    }
+
+    // A wrapper with a random key. Handles it issues cannot be unwrapped once it is dropped.
+    pub fn generate() -> Result<Self> {
+        let crypto = default_provider();
+        let mut key = [0u8; WRAPPING_KEY_LENGTH];
+        crypto.random_bytes(&mut key)?;
+        Ok(KeyWrapper { key, crypto })
+    }
+
+    // Seals and opens handles, and draws their nonces, with `crypto` instead of the default
+    // provider. Handles stay compatible across providers.
+    pub fn with_crypto_provider(mut self, crypto: Arc<dyn CryptoProvider>) -> Self {
+        self.crypto = crypto;
+        self
+    }
+
+    // Seals `private_key` into a KEY_HANDLE_LENGTH byte handle bound to `application`.
+    pub fn wrap(&self, application: &[u8], private_key: &[u8]) -> Result<Vec<u8>> {
+        if private_key.len() != PRIVATE_KEY_LENGTH {
+            return Err(U2fError::InvalidPrivateKey);
+        }
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        self.crypto.random_bytes(&mut nonce)?;
+
+        let aad = associated_data(application);
+        let sealed = self.crypto.seal_aes_256_gcm(&self.key, &nonce, &aad, private_key)?;
+
+        let mut key_handle = vec![KEY_HANDLE_VERSION];
+        key_handle.extend_from_slice(&nonce);
+        key_handle.extend_from_slice(&sealed);
+        Ok(key_handle)
+    }
+
+    // Recovers the private key. Fails with `WrongKeyHandler` for handles issued by another
+    // wrapper or for another application.
+    pub fn unwrap(&self, application: &[u8], key_handle: &[u8]) -> Result<Vec<u8>> {
+        if key_handle.len() != KEY_HANDLE_LENGTH || key_handle[0] != KEY_HANDLE_VERSION {
+            return Err(U2fError::WrongKeyHandler);
+        }
+
+        let (nonce, sealed) = key_handle[1..].split_at(NONCE_LENGTH);
+
+        let aad = associated_data(application);
+        self.crypto.open_aes_256_gcm(&self.key, nonce, &aad, sealed).map_err(|_e| U2fError::WrongKeyHandler)
+    }
+}
+
+// The handle version is authenticated along with the application parameter.
+fn associated_data(application: &[u8]) -> Vec<u8> {
+    [&[KEY_HANDLE_VERSION][..], application].concat()
+}
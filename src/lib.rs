@@ -8,16 +8,44 @@ extern crate bytes;
 extern crate byteorder;
 extern crate chrono;
 extern crate base64;
+#[cfg(feature = "openssl")]
 extern crate openssl;
+extern crate hex;
+
+#[cfg(not(any(feature = "openssl", feature = "rust-crypto")))]
+compile_error!("u2f needs a crypto backend: enable the `openssl` or the `rust-crypto` feature");
 
 mod util;
+// Which parts of the DER reader and writer are used depends on the backend and features.
+#[allow(dead_code)]
+mod der;
 
 pub mod u2ferror;
 pub mod register;
 pub mod messages;
 pub mod protocol;
 pub mod authorization;
+pub mod clock;
+pub mod attestation;
+pub mod metadata;
+pub mod device;
+pub mod appid;
+pub mod raw;
+pub mod hid;
+pub mod ble;
+pub mod nfc;
+pub mod key_wrapper;
+pub mod crypto_provider;
+pub mod challenge_store;
+#[cfg(feature = "openssl")]
 mod crypto;
 
+#[cfg(any(test, feature = "soft-token"))]
+pub mod soft_token;
+
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -1,48 +1,440 @@
-// As defined by FIDO U2F Javascript API.
-// https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-javascript-api.html#registration
+// As defined by FIDO U2F Javascript API v1.1.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-javascript-api-v1.2-ps-20170411.html
 
-#[derive(Serialize)]
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::appid::{AppId, Origin};
+use crate::u2ferror::U2fError;
+
+// Values of the `type` field of the request and response envelopes.
+pub const REGISTER_REQUEST_TYPE: &str = "u2f_register_request";
+pub const REGISTER_RESPONSE_TYPE: &str = "u2f_register_response";
+pub const SIGN_REQUEST_TYPE: &str = "u2f_sign_request";
+pub const SIGN_RESPONSE_TYPE: &str = "u2f_sign_response";
+
+// Revision of the JavaScript API spoken by the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApiVersion {
+    // High level API of v1.0: `u2f.register(registerRequests, signRequests, ...)` and
+    // `u2f.sign(signRequests, ...)`, every request carrying its own app ID and challenge.
+    V1_0,
+    // Single `u2f.register`/`u2f.sign` request listing the `registeredKeys`.
+    #[default]
+    V1_1,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct U2fRegisterRequest {
+    #[serde(rename = "type")]
+    pub request_type: String,
     pub app_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u32>,
     pub register_requests: Vec<RegisterRequest>,
     pub registered_keys: Vec<RegisteredKey>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RegisterRequest {
     pub version: String,
     pub challenge: String
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RegisteredKey {
     pub version: String,
     pub key_handle: Option<String>,
+    // "bt", "ble", "nfc", "usb" or "usb-internal".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transports: Option<Vec<String>>,
     pub app_id: String
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RegisterResponse {
     pub registration_data: String,
+    // Not sent by v1.0 clients.
+    #[serde(default)]
     pub version: String,
     pub client_data: String
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct U2fSignRequest {
+    #[serde(rename = "type")]
+    pub request_type: String,
     pub app_id: String,
     pub challenge: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u32>,
     pub registered_keys: Vec<RegisteredKey>
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct SignResponse {
     pub key_handle: String,
     pub signature_data: String,
     pub client_data: String
-}
\ No newline at end of file
+}
+
+// Register request of the v1.0 API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyRegisterRequest {
+    pub version: String,
+    pub challenge: String,
+    pub app_id: String,
+}
+
+// Sign request of the v1.0 API, one per registered key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignRequest {
+    pub version: String,
+    pub challenge: String,
+    pub key_handle: String,
+    pub app_id: String,
+}
+
+// Arguments of the v1.0 `u2f.register` call. `sign_requests` lists the keys already registered.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct U2fLegacyRegisterRequest {
+    pub register_requests: Vec<LegacyRegisterRequest>,
+    pub sign_requests: Vec<SignRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u32>,
+}
+
+// Arguments of the v1.0 `u2f.sign` call.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct U2fLegacySignRequest {
+    pub sign_requests: Vec<SignRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u32>,
+}
+
+// Register request in the wire format of the configured `ApiVersion`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum RegisterRequestMessage {
+    V1_1(U2fRegisterRequest),
+    V1_0(U2fLegacyRegisterRequest),
+}
+
+// Sign request in the wire format of the configured `ApiVersion`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SignRequestMessage {
+    V1_1(U2fSignRequest),
+    V1_0(U2fLegacySignRequest),
+}
+
+// Response envelope of a register request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct U2fRegisterResponse {
+    #[serde(rename = "type")]
+    pub response_type: String,
+    pub response_data: RegisterResponseData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u32>,
+}
+
+// Response envelope of a sign request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct U2fSignResponse {
+    #[serde(rename = "type")]
+    pub response_type: String,
+    pub response_data: SignResponseData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u32>,
+}
+
+// What the browser hands back for a register request: either a response or an error.
+// v1.0 clients pass this directly to their callback, without an envelope.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum RegisterResponseData {
+    Error(ErrorResponse),
+    Response(RegisterResponse),
+}
+
+// What the browser hands back for a sign request: either a response or an error.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SignResponseData {
+    Error(ErrorResponse),
+    Response(SignResponse),
+}
+
+// Error reported by the client, serialized as `{"errorCode": n, "errorMessage": "..."}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorResponse {
+    OtherError(Option<String>),
+    BadRequest(Option<String>),
+    ConfigurationUnsupported(Option<String>),
+    DeviceIneligible(Option<String>),
+    Timeout(Option<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorFields {
+    error_code: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn from_code(code: u8, message: Option<String>) -> Option<ErrorResponse> {
+        match code {
+            1 => Some(ErrorResponse::OtherError(message)),
+            2 => Some(ErrorResponse::BadRequest(message)),
+            3 => Some(ErrorResponse::ConfigurationUnsupported(message)),
+            4 => Some(ErrorResponse::DeviceIneligible(message)),
+            5 => Some(ErrorResponse::Timeout(message)),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            ErrorResponse::OtherError(_) => 1,
+            ErrorResponse::BadRequest(_) => 2,
+            ErrorResponse::ConfigurationUnsupported(_) => 3,
+            ErrorResponse::DeviceIneligible(_) => 4,
+            ErrorResponse::Timeout(_) => 5,
+        }
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            ErrorResponse::OtherError(message)
+            | ErrorResponse::BadRequest(message)
+            | ErrorResponse::ConfigurationUnsupported(message)
+            | ErrorResponse::DeviceIneligible(message)
+            | ErrorResponse::Timeout(message) => message.as_deref(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorResponse::OtherError(_) => "OTHER_ERROR",
+            ErrorResponse::BadRequest(_) => "BAD_REQUEST",
+            ErrorResponse::ConfigurationUnsupported(_) => "CONFIGURATION_UNSUPPORTED",
+            ErrorResponse::DeviceIneligible(_) => "DEVICE_INELIGIBLE",
+            ErrorResponse::Timeout(_) => "TIMEOUT",
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "{} ({}): {}", self.name(), self.code(), message),
+            None => write!(f, "{} ({})", self.name(), self.code()),
+        }
+    }
+}
+
+impl Serialize for ErrorResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ErrorFields {
+            error_code: self.code(),
+            error_message: self.message().map(|m| m.to_string()),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = ErrorFields::deserialize(deserializer)?;
+        let code = fields.error_code;
+
+        ErrorResponse::from_code(code, fields.error_message)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown error code {}", code)))
+    }
+}
+
+// Client data as collected by the browser and hashed into the signed message.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#client-data
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientData {
+    pub typ: String,
+    pub challenge: String,
+    pub origin: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid_pubkey: Option<serde_json::Value>,
+}
+
+impl ClientData {
+    pub fn from_bytes(client_data: &[u8]) -> Result<ClientData, U2fError> {
+        serde_json::from_slice(client_data).map_err(|_e| U2fError::InvalidClientData)
+    }
+}
+
+// Trusted facet list served at the AppID URL.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-appid-and-facets-v1.2-ps-20170411.html#the-appid-and-facetid-assertions
+pub const TRUSTED_FACETS_CONTENT_TYPE: &str = "application/fido.trusted-apps+json";
+pub const ANDROID_FACET_PREFIX: &str = "android:apk-key-hash:";
+pub const IOS_FACET_PREFIX: &str = "ios:bundle-id:";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustedFacetsList {
+    pub trusted_facets: Vec<TrustedFacets>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrustedFacets {
+    pub version: Version,
+    #[serde(default)]
+    pub ids: Vec<String>,
+}
+
+// A FacetID: the https origin of a web application, or the identity of a mobile app.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Facet {
+    Web(String),
+    // Base64 encoded SHA-1 hash of the APK signing certificate.
+    Android(String),
+    Ios(String),
+}
+
+impl Facet {
+    // Parses a facet of a known scheme; web facets are normalized to their origin.
+    pub fn parse(id: &str) -> Option<Facet> {
+        if let Some(hash) = id.strip_prefix(ANDROID_FACET_PREFIX) {
+            return Some(Facet::Android(hash.to_string()));
+        }
+        if let Some(bundle_id) = id.strip_prefix(IOS_FACET_PREFIX) {
+            return Some(Facet::Ios(bundle_id.to_string()));
+        }
+
+        match Origin::parse(id) {
+            Ok(origin) if origin.is_https() => Some(Facet::Web(origin.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Facet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Facet::Web(origin) => write!(f, "{}", origin),
+            Facet::Android(hash) => write!(f, "{}{}", ANDROID_FACET_PREFIX, hash),
+            Facet::Ios(bundle_id) => write!(f, "{}{}", IOS_FACET_PREFIX, bundle_id),
+        }
+    }
+}
+
+impl TrustedFacetsList {
+    // Facets of the highest 1.x version listed, as clients implementing version 1.0 pick it.
+    // Entries of unknown schemes are skipped.
+    pub fn facets(&self) -> Vec<Facet> {
+        self.trusted_facets
+            .iter()
+            .filter(|entry| entry.version.major == 1)
+            .max_by_key(|entry| entry.version)
+            .map(|entry| entry.ids.iter().filter_map(|id| Facet::parse(id)).collect())
+            .unwrap_or_default()
+    }
+
+    // Whether the client data `origin` may act for `app_id`. Besides the origins sharing the
+    // AppID's host, only listed facets are accepted, and listed web origins must share the
+    // AppID's effective domain.
+    pub fn is_authorized(&self, app_id: &AppId, origin: &str) -> bool {
+        if app_id.is_authorized(origin) {
+            return true;
+        }
+
+        let caller = match Facet::parse(origin) {
+            Some(facet) => facet,
+            None => return false,
+        };
+
+        self.facets().into_iter().any(|facet| {
+            facet == caller
+                && match &facet {
+                    Facet::Web(origin) => app_id.shares_effective_domain(origin),
+                    _ => true,
+                }
+        })
+    }
+}
+
+// Builds the trusted facet list to serve at the AppID URL, with
+// `TRUSTED_FACETS_CONTENT_TYPE` as content type.
+#[derive(Clone, Debug)]
+pub struct TrustedFacetsBuilder {
+    app_id: String,
+    ids: Vec<String>,
+}
+
+impl TrustedFacetsBuilder {
+    pub fn new(app_id: String) -> Self {
+        TrustedFacetsBuilder { app_id, ids: vec![] }
+    }
+
+    // Adds an https origin, e.g. "https://login.example.com".
+    pub fn with_web_facet(mut self, origin: &str) -> Self {
+        self.ids.push(origin.to_string());
+        self
+    }
+
+    pub fn with_android_facet(mut self, apk_key_hash: &str) -> Self {
+        self.ids.push(format!("{}{}", ANDROID_FACET_PREFIX, apk_key_hash));
+        self
+    }
+
+    pub fn with_ios_facet(mut self, bundle_id: &str) -> Self {
+        self.ids.push(format!("{}{}", IOS_FACET_PREFIX, bundle_id));
+        self
+    }
+
+    // Fails with `InvalidFacet` for web facets clients would ignore: non-https origins and
+    // origins outside the AppID's effective domain.
+    pub fn build(self) -> Result<TrustedFacetsList, U2fError> {
+        let app_id = AppId::parse(&self.app_id)?;
+
+        let mut ids = vec![];
+        for id in &self.ids {
+            let facet = Facet::parse(id).ok_or(U2fError::InvalidFacet)?;
+            if let Facet::Web(origin) = &facet {
+                if !app_id.shares_effective_domain(origin) {
+                    return Err(U2fError::InvalidFacet);
+                }
+            }
+
+            let id = facet.to_string();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        Ok(TrustedFacetsList {
+            trusted_facets: vec![TrustedFacets { version: Version { major: 1, minor: 0 }, ids }],
+        })
+    }
+}
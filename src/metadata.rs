@@ -0,0 +1,253 @@
+// FIDO Metadata Service (MDS3) BLOB, loaded from local storage.
+// https://fidoalliance.org/specs/mds/fido-metadata-service-v3.0-ps-20210518.html
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use base64::{decode_config, STANDARD, URL_SAFE_NO_PAD};
+
+use crate::attestation::{verify_chain, AttestationTrustStore};
+use crate::crypto_provider::{default_provider, CryptoProvider};
+use crate::der;
+use crate::register::Registration;
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+#[derive(Deserialize)]
+struct BlobHeader {
+    alg: String,
+    #[serde(default)]
+    x5c: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataBlobPayload {
+    pub legal_header: Option<String>,
+    pub no: u64,
+    pub next_update: String,
+    pub entries: Vec<MetadataEntry>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataEntry {
+    pub aaid: Option<String>,
+    pub aaguid: Option<String>,
+    #[serde(default)]
+    pub attestation_certificate_key_identifiers: Vec<String>,
+    pub metadata_statement: Option<MetadataStatement>,
+    #[serde(default)]
+    pub status_reports: Vec<StatusReport>,
+    pub time_of_last_status_change: Option<String>,
+}
+
+impl MetadataEntry {
+    // Status of the most recent report, reports being ordered by effective date.
+    pub fn status(&self) -> Option<&AuthenticatorStatus> {
+        self.status_reports
+            .iter()
+            .max_by(|a, b| a.effective_date.cmp(&b.effective_date))
+            .map(|report| &report.status)
+    }
+
+    // Whether any report flags the authenticator as compromised or revoked.
+    pub fn is_compromised(&self) -> bool {
+        self.status_reports.iter().any(|report| report.status.is_compromised())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataStatement {
+    pub description: String,
+    pub legal_header: Option<String>,
+    pub aaid: Option<String>,
+    pub aaguid: Option<String>,
+    pub protocol_family: Option<String>,
+    pub authenticator_version: Option<u32>,
+    #[serde(default)]
+    pub attestation_certificate_key_identifiers: Vec<String>,
+    // Base64 (standard alphabet) DER certificates.
+    #[serde(default)]
+    pub attestation_root_certificates: Vec<String>,
+    pub icon: Option<String>,
+}
+
+impl MetadataStatement {
+    // Builds a trust store from the statement's attestation root certificates.
+    pub fn trust_store(&self) -> Result<AttestationTrustStore> {
+        let mut store = AttestationTrustStore::new();
+
+        for cert in &self.attestation_root_certificates {
+            let der = decode_config(cert, STANDARD).map_err(|_e| U2fError::BadCertificate)?;
+            store.add_root_der(&der)?;
+        }
+
+        Ok(store)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusReport {
+    pub status: AuthenticatorStatus,
+    pub effective_date: Option<String>,
+    pub authenticator_version: Option<u32>,
+    pub certificate: Option<String>,
+    pub url: Option<String>,
+    pub certification_descriptor: Option<String>,
+    pub certificate_number: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuthenticatorStatus {
+    NotFidoCertified,
+    FidoCertified,
+    UserVerificationBypass,
+    AttestationKeyCompromise,
+    UserKeyRemoteCompromise,
+    UserKeyPhysicalCompromise,
+    UpdateAvailable,
+    Revoked,
+    SelfAssertionSubmitted,
+    #[serde(rename = "FIDO_CERTIFIED_L1")]
+    FidoCertifiedL1,
+    #[serde(rename = "FIDO_CERTIFIED_L1plus")]
+    FidoCertifiedL1Plus,
+    #[serde(rename = "FIDO_CERTIFIED_L2")]
+    FidoCertifiedL2,
+    #[serde(rename = "FIDO_CERTIFIED_L2plus")]
+    FidoCertifiedL2Plus,
+    #[serde(rename = "FIDO_CERTIFIED_L3")]
+    FidoCertifiedL3,
+    #[serde(rename = "FIDO_CERTIFIED_L3plus")]
+    FidoCertifiedL3Plus,
+    #[serde(other)]
+    Unknown,
+}
+
+impl AuthenticatorStatus {
+    pub fn is_compromised(&self) -> bool {
+        matches!(
+            self,
+            AuthenticatorStatus::UserVerificationBypass
+                | AuthenticatorStatus::AttestationKeyCompromise
+                | AuthenticatorStatus::UserKeyRemoteCompromise
+                | AuthenticatorStatus::UserKeyPhysicalCompromise
+                | AuthenticatorStatus::Revoked
+        )
+    }
+}
+
+// Verified MDS3 BLOB, indexed by attestation certificate key identifier.
+#[derive(Clone, Debug)]
+pub struct MetadataService {
+    payload: MetadataBlobPayload,
+    by_key_identifier: HashMap<String, usize>,
+}
+
+impl MetadataService {
+    // Verifies the BLOB (a JWT) against the DER encoded MDS root certificate and indexes its entries.
+    pub fn from_blob(blob: &str, root: &[u8]) -> Result<Self> {
+        MetadataService::from_blob_with(&*default_provider(), blob, root)
+    }
+
+    // Like `from_blob`, checking the chain and the signature with `crypto`.
+    pub fn from_blob_with(crypto: &dyn CryptoProvider, blob: &str, root: &[u8]) -> Result<Self> {
+        let parts: Vec<&str> = blob.trim().split('.').collect();
+        if parts.len() != 3 {
+            return Err(U2fError::InvalidMetadataBlob);
+        }
+
+        let header = decode_config(parts[0], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+        let header: BlobHeader = serde_json::from_slice(&header).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+        let signature = decode_config(parts[2], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+
+        // The first x5c certificate signs the BLOB, the others lead up to the root.
+        let mut chain = vec![];
+        for cert in &header.x5c {
+            let der = decode_config(cert, STANDARD).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+            crypto.parse_certificate(&der)?;
+            chain.push(der);
+        }
+        if chain.is_empty() {
+            return Err(U2fError::InvalidMetadataBlob);
+        }
+        let signer = chain.remove(0);
+
+        crypto.parse_certificate(root)?;
+        if !verify_chain(crypto, &signer, &[root.to_vec()], &chain)? {
+            return Err(U2fError::NotTrustedAnchor);
+        }
+
+        let signature = match header.alg.as_str() {
+            "ES256" => ecdsa_raw_to_der(&signature)?,
+            "RS256" => signature,
+            _ => return Err(U2fError::InvalidMetadataBlob),
+        };
+
+        let signed_data = &blob.trim()[..parts[0].len() + 1 + parts[1].len()];
+        if !crypto.verify_signature(&signer, signed_data.as_bytes(), &signature)? {
+            return Err(U2fError::BadSignature);
+        }
+
+        let payload = decode_config(parts[1], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+        let payload: MetadataBlobPayload = serde_json::from_slice(&payload).map_err(|_e| U2fError::InvalidMetadataBlob)?;
+
+        let mut by_key_identifier = HashMap::new();
+        for (index, entry) in payload.entries.iter().enumerate() {
+            for key_identifier in &entry.attestation_certificate_key_identifiers {
+                by_key_identifier.insert(key_identifier.to_lowercase(), index);
+            }
+        }
+
+        Ok(MetadataService { payload, by_key_identifier })
+    }
+
+    // Reads and verifies a BLOB stored on disk.
+    pub fn from_file<P: AsRef<Path>>(path: P, root: &[u8]) -> Result<Self> {
+        let blob = std::fs::read_to_string(path).map_err(U2fError::IoError)?;
+        MetadataService::from_blob(&blob, root)
+    }
+
+    pub fn payload(&self) -> &MetadataBlobPayload {
+        &self.payload
+    }
+
+    // Entry for the attestation certificate key identifier (hex encoded SHA-1 of the public key).
+    pub fn entry(&self, key_identifier: &str) -> Option<&MetadataEntry> {
+        self.by_key_identifier
+            .get(&key_identifier.to_lowercase())
+            .map(|index| &self.payload.entries[*index])
+    }
+
+    // Entry for the DER encoded attestation certificate.
+    pub fn entry_for_certificate(&self, attestation_cert: &[u8]) -> Option<&MetadataEntry> {
+        let crypto = default_provider();
+        let public_key = crypto.parse_certificate(attestation_cert).ok()?.public_key;
+
+        self.entry(&hex::encode(crypto.sha1(&public_key)))
+    }
+
+    // Entry for the registration's attestation certificate.
+    pub fn lookup(&self, registration: &Registration) -> Option<&MetadataEntry> {
+        registration
+            .attestation_cert
+            .as_ref()
+            .and_then(|cert| self.entry_for_certificate(cert))
+    }
+}
+
+// Converts a JWS style ECDSA signature (r || s) into its DER encoding.
+fn ecdsa_raw_to_der(signature: &[u8]) -> Result<Vec<u8>> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err(U2fError::BadSignature);
+    }
+
+    let (r, s) = signature.split_at(signature.len() / 2);
+    Ok(der::sequence(&[&der::integer(r), &der::integer(s)]))
+}
@@ -0,0 +1,122 @@
+// U2F over NFC: applet selection and ISO 7816-4 response chaining on top of `raw`.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-nfc-protocol-v1.2-ps-20170411.html
+
+use crate::raw::{Apdu, ApduEncoding, AuthenticateControl, Request, Response, CLA};
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+// Application identifier of the U2F applet.
+pub const U2F_AID: [u8; 8] = [0xa0, 0x00, 0x00, 0x06, 0x47, 0x2f, 0x00, 0x01];
+
+pub const INS_SELECT: u8 = 0xa4;
+// SELECT by DF name, first or only occurrence.
+pub const SELECT_BY_NAME: u8 = 0x04;
+pub const INS_GET_RESPONSE: u8 = 0xc0;
+// SW1 announcing more response bytes, SW2 giving how many (0x00 for 256 or more).
+pub const SW1_MORE_DATA: u8 = 0x61;
+
+// Largest response accepted from a chain of GET RESPONSE commands.
+const MAX_RESPONSE_SIZE: usize = 0x10000;
+
+// Exchanges APDUs with a card, e.g. through PC/SC.
+pub trait Transport {
+    // Sends a command APDU and returns the response APDU, status word included.
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>>;
+}
+
+// A U2F session with an NFC token. The applet is selected before the first command.
+pub struct NfcSession<T: Transport> {
+    transport: T,
+    encoding: ApduEncoding,
+    selected: bool,
+}
+
+impl<T: Transport> NfcSession<T> {
+    pub fn new(transport: T) -> Self {
+        NfcSession { transport, encoding: ApduEncoding::Short, selected: false }
+    }
+
+    // APDU length encoding used for U2F commands. Defaults to short, which every NFC token
+    // accepts; key handles longer than 190 bytes need extended length.
+    pub fn with_encoding(mut self, encoding: ApduEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    // Selects the U2F applet, returning the version string it answers with ("U2F_V2").
+    pub fn select(&mut self) -> Result<String> {
+        let select = Apdu {
+            cla: CLA,
+            ins: INS_SELECT,
+            p1: SELECT_BY_NAME,
+            p2: 0x00,
+            data: U2F_AID.to_vec(),
+            ne: Some(0x100),
+        };
+
+        let version = self.transmit(&select.encode(ApduEncoding::Short)?)?;
+        self.selected = true;
+
+        String::from_utf8(version).map_err(|_e| U2fError::InvalidApdu)
+    }
+
+    // Sends a U2F request, returning the response data once the status word is SW_NO_ERROR.
+    // Other status words become `ApduStatus`.
+    pub fn send(&mut self, request: &Request) -> Result<Vec<u8>> {
+        if !self.selected {
+            self.select()?;
+        }
+
+        let apdu = request.encode(self.encoding)?;
+        self.transmit(&apdu)
+    }
+
+    pub fn version(&mut self) -> Result<String> {
+        let version = self.send(&Request::Version)?;
+        String::from_utf8(version).map_err(|_e| U2fError::InvalidApdu)
+    }
+
+    // Registration data for `parse_registration`.
+    pub fn register(&mut self, app_id: &str, client_data: &[u8]) -> Result<Vec<u8>> {
+        self.send(&Request::register(app_id, client_data))
+    }
+
+    // Signature data for `parse_sign_response`.
+    pub fn authenticate(&mut self, control: AuthenticateControl, app_id: &str, client_data: &[u8], key_handle: &[u8]) -> Result<Vec<u8>> {
+        self.send(&Request::authenticate(control, app_id, client_data, key_handle))
+    }
+
+    // Transmits the command and collects a chained response with GET RESPONSE.
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let mut response = Response::decode(&self.transport.transmit(apdu)?)?;
+        let mut data = std::mem::take(&mut response.data);
+
+        while response.status >> 8 == SW1_MORE_DATA as u16 {
+            let get_response = Apdu {
+                cla: CLA,
+                ins: INS_GET_RESPONSE,
+                p1: 0x00,
+                p2: 0x00,
+                data: vec![],
+                ne: Some(match response.status & 0xff {
+                    0 => 0x100,
+                    remaining => remaining as usize,
+                }),
+            };
+
+            response = Response::decode(&self.transport.transmit(&get_response.encode(ApduEncoding::Short)?)?)?;
+            data.append(&mut response.data);
+            if data.len() > MAX_RESPONSE_SIZE {
+                return Err(U2fError::InvalidApdu);
+            }
+        }
+
+        Response::new(data, response.status).into_data()
+    }
+}
@@ -3,9 +3,15 @@ use crate::messages::*;
 use crate::register::*;
 use crate::authorization::*;
 
-use base64::{encode_config, decode_config, URL_SAFE_NO_PAD};
+use base64::{decode_config, URL_SAFE_NO_PAD};
 use chrono::prelude::*;
-use time::Duration;
+use chrono::Duration;
+use std::sync::Arc;
+use crate::appid::AppId;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto_provider::{default_provider, CryptoProvider};
+use crate::challenge_store::ChallengeStore;
+use crate::attestation::{AttestationPolicy, AttestationTrustStore};
 use crate::u2ferror::U2fError;
 
 type Result<T> = ::std::result::Result<T, U2fError>;
@@ -13,50 +19,181 @@ type Result<T> = ::std::result::Result<T, U2fError>;
 #[derive(Clone)]
 pub struct U2f {
     app_id: String,
+    // `None` when `new` was given an invalid app ID, which every response then fails with.
+    parsed_app_id: Option<AppId>,
+    challenge_ttl: Duration,
+    clock_skew: Duration,
+    clock: Arc<dyn Clock>,
+    crypto: Arc<dyn CryptoProvider>,
+    challenge_store: Option<Arc<dyn ChallengeStore>>,
+    trusted_facets: Option<Arc<TrustedFacetsList>>,
+    attestation_store: Arc<AttestationTrustStore>,
+    attestation_policy: AttestationPolicy,
+    allow_silent_authentication: bool,
+    counter_policy: CounterPolicy,
+    timeout_seconds: Option<u32>,
+    api_version: ApiVersion,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Challenge {
     pub app_id: String,
+    // Unpadded base64url encoding of the random challenge bytes. This exact string is sent
+    // to the client in register and sign requests and echoed back in the client data.
     pub challenge: String,
     pub timestamp: String,
 }
 
+// Registration that produced a verified sign response.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedRegistration {
+    // Position of the registration in the slice given to `sign_response_any`.
+    pub index: usize,
+    // Verified authorization; `counter` is the value to store for the registration.
+    pub authorization: Authorization,
+}
+
 impl Challenge {
     pub fn new() -> Self {
+        Challenge::default()
+    }
+
+    pub fn from_bytes(app_id: String, challenge: &[u8], timestamp: DateTime<Utc>) -> Self {
         Challenge {
-            app_id: String::new(),
-            challenge: String::new(),
-            timestamp: String::new()
+            app_id,
+            challenge: get_encoded(challenge),
+            timestamp: format!("{:?}", timestamp),
         }
     }
+
+    // The raw challenge bytes.
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        decode_config(&self.challenge[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidChallenge)
+    }
 }
 
 impl U2f {
-    // The app ID is a string used to uniquely identify an U2F app
+    // The app ID is a string used to uniquely identify an U2F app. An invalid one is only
+    // reported once a response comes in, as `InvalidAppId`; prefer `try_new`.
     pub fn new(app_id: String) -> Self {
         U2f {
-            app_id: app_id,
+            parsed_app_id: AppId::parse(&app_id).ok(),
+            app_id,
+            challenge_ttl: Duration::seconds(300),
+            clock_skew: Duration::seconds(0),
+            clock: Arc::new(SystemClock),
+            crypto: default_provider(),
+            challenge_store: None,
+            trusted_facets: None,
+            attestation_store: Arc::new(AttestationTrustStore::new()),
+            attestation_policy: AttestationPolicy::Any,
+            allow_silent_authentication: false,
+            counter_policy: CounterPolicy::StrictIncrease,
+            timeout_seconds: None,
+            api_version: ApiVersion::V1_1,
         }
     }
 
+    // Like `new`, but fails with `InvalidAppId` unless `app_id` is an https URL below a
+    // public suffix.
+    pub fn try_new(app_id: String) -> Result<Self> {
+        let u2f = U2f::new(app_id);
+        u2f.parsed_app_id()?;
+        Ok(u2f)
+    }
+
+    // How long an issued challenge may be answered. Defaults to 300 seconds.
+    pub fn with_challenge_ttl(mut self, ttl: Duration) -> Self {
+        self.challenge_ttl = ttl;
+        self
+    }
+
+    // How far in the future a challenge timestamp may lie, to tolerate clock
+    // differences between servers. Defaults to zero.
+    pub fn with_clock_skew(mut self, skew: Duration) -> Self {
+        self.clock_skew = skew;
+        self
+    }
+
+    // Replaces the system clock used to stamp and expire challenges.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Replaces the default crypto backend for signature checks, attestation chains and hashing.
+    pub fn with_crypto_provider(mut self, crypto: Arc<dyn CryptoProvider>) -> Self {
+        self.crypto = crypto;
+        self
+    }
+
+    // Records issued challenges in `store` and consumes them in `register_response` and
+    // `sign_response`, so that a replayed response fails with `ChallengeReplayed`.
+    pub fn with_challenge_store(mut self, store: Arc<dyn ChallengeStore>) -> Self {
+        self.challenge_store = Some(store);
+        self
+    }
+
+    // Also accepts responses from the facets of `trusted_facets`, the list served at the
+    // AppID URL. Without it only origins on the AppID's host are accepted.
+    pub fn with_trusted_facets(mut self, trusted_facets: TrustedFacetsList) -> Self {
+        self.trusted_facets = Some(Arc::new(trusted_facets));
+        self
+    }
+
+    // Validates attestation certificates against `store` according to `policy` during registration.
+    pub fn with_attestation(mut self, store: AttestationTrustStore, policy: AttestationPolicy) -> Self {
+        self.attestation_store = Arc::new(store);
+        self.attestation_policy = policy;
+        self
+    }
+
+    // Accepts sign responses whose user presence bit is clear. Only enable this for
+    // flows that deliberately authenticate without a touch (dont-enforce-user-presence).
+    pub fn with_silent_authentication(mut self, allow: bool) -> Self {
+        self.allow_silent_authentication = allow;
+        self
+    }
+
+    // How the counter of a sign response is compared with the stored one. Defaults to `StrictIncrease`.
+    pub fn with_counter_policy(mut self, policy: CounterPolicy) -> Self {
+        self.counter_policy = policy;
+        self
+    }
+
+    // Sets `timeoutSeconds` on the register and sign requests sent to the client.
+    pub fn with_timeout_seconds(mut self, timeout: u32) -> Self {
+        self.timeout_seconds = Some(timeout);
+        self
+    }
+
+    // Wire format produced by `register_message` and `sign_message`. Defaults to v1.1.
+    pub fn with_api_version(mut self, version: ApiVersion) -> Self {
+        self.api_version = version;
+        self
+    }
+
     pub fn generate_challenge(&self) -> Result<Challenge> {
-        let utc: DateTime<Utc> = Utc::now();
+        let utc = self.clock.now();
 
-        let challenge_bytes = generate_challenge(32)?; 
-        let challenge = Challenge {
-            challenge : encode_config(&challenge_bytes, URL_SAFE_NO_PAD),
-            timestamp : format!("{:?}", utc),
-            app_id : self.app_id.clone()
-        };
-        
-        Ok(challenge.clone())
+        let mut challenge_bytes = [0u8; 32];
+        self.crypto.random_bytes(&mut challenge_bytes)?;
+
+        let challenge = Challenge::from_bytes(self.app_id.clone(), &challenge_bytes, utc);
+        if let Some(store) = self.challenge_store.as_ref() {
+            store.issue(&challenge, utc + self.challenge_ttl)?;
+        }
+
+        Ok(challenge)
     }
 
     pub fn request(&self, challenge: Challenge, registrations: Vec<Registration>) -> Result<U2fRegisterRequest> {
         let u2f_request = U2fRegisterRequest {
+            request_type: REGISTER_REQUEST_TYPE.into(),
             app_id : self.app_id.clone(),
+            timeout_seconds: self.timeout_seconds,
+            request_id: None,
             register_requests: self.register_request(challenge),
             registered_keys: self.registered_keys(registrations)
         };
@@ -64,6 +201,26 @@ impl U2f {
         Ok(u2f_request)
     }
 
+    // Register request in the format of the configured API version.
+    pub fn register_message(&self, challenge: Challenge, registrations: Vec<Registration>) -> Result<RegisterRequestMessage> {
+        match self.api_version {
+            ApiVersion::V1_1 => Ok(RegisterRequestMessage::V1_1(self.request(challenge, registrations)?)),
+            ApiVersion::V1_0 => {
+                let request = LegacyRegisterRequest {
+                    version: U2F_V2.into(),
+                    challenge: challenge.challenge.clone(),
+                    app_id: self.app_id.clone(),
+                };
+
+                Ok(RegisterRequestMessage::V1_0(U2fLegacyRegisterRequest {
+                    register_requests: vec![request],
+                    sign_requests: self.legacy_sign_requests(&challenge, registrations),
+                    timeout_seconds: self.timeout_seconds,
+                }))
+            }
+        }
+    }
+
     fn register_request(&self, challenge: Challenge) -> Vec<RegisterRequest> {
         let mut requests: Vec<RegisterRequest> = vec![];
 
@@ -77,14 +234,56 @@ impl U2f {
     }
 
     pub fn register_response(&self, challenge: Challenge, response: RegisterResponse) -> Result<Registration> {
-        if expiration(challenge.timestamp) > Duration::seconds(300) {
+        self.check_challenge(&challenge)?;
+
+        let registration_data: Vec<u8> = decode_config(&response.registration_data[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidRegistrationData)?;
+        let client_data: Vec<u8> = decode_config(&response.client_data[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidClientData)?;
+
+        verify_client_data(&client_data, REGISTER_TYPE, &challenge.challenge, self.parsed_app_id()?, self.trusted_facets.as_deref())?;
+
+        let mut registration = parse_registration_with(&*self.crypto, self.app_id.clone(), client_data, registration_data)?;
+        self.consume_challenge(&challenge)?;
+        registration.metadata.created_at = Some(self.clock.now());
+
+        if let Some(cert) = registration.attestation_cert.as_ref() {
+            self.attestation_store.check_with(&*self.crypto, &self.attestation_policy, cert)?;
+        }
+
+        Ok(registration)
+    }
+
+    // The challenge comes back from the client, so its app ID is only checked against ours,
+    // never trusted.
+    fn check_challenge(&self, challenge: &Challenge) -> Result<()> {
+        if challenge.app_id != self.app_id {
+            return Err(U2fError::InvalidAppId);
+        }
+
+        let age = expiration(&challenge.timestamp, self.clock.now())?;
+
+        if age > self.challenge_ttl {
             return Err(U2fError::ChallengeExpired);
         }
 
-        let registration_data: Vec<u8> = decode_config(&response.registration_data[..], URL_SAFE_NO_PAD).unwrap();
-        let client_data: Vec<u8> = decode_config(&response.client_data[..], URL_SAFE_NO_PAD).unwrap();
+        if -age > self.clock_skew {
+            return Err(U2fError::ChallengeNotYetValid);
+        }
+
+        Ok(())
+    }
 
-        parse_registration(challenge.app_id, client_data, registration_data)
+    fn parsed_app_id(&self) -> Result<&AppId> {
+        self.parsed_app_id.as_ref().ok_or(U2fError::InvalidAppId)
+    }
+
+    // A challenge is used up by the first response whose client data and signature verify,
+    // so that a forged response cannot burn it for the genuine one. Later checks (attestation,
+    // user presence, counter) run on a challenge that is already used up.
+    fn consume_challenge(&self, challenge: &Challenge) -> Result<()> {
+        match self.challenge_store.as_ref() {
+            Some(store) => store.consume(challenge, self.clock.now()),
+            None => Ok(()),
+        }
     }
 
     fn registered_keys(&self, registrations: Vec<Registration>) -> Vec<RegisteredKey> {
@@ -104,19 +303,41 @@ impl U2f {
             keys.push(get_registered_key(self.app_id.clone(), registration.key_handle));
         }
 
-        let signed_request = U2fSignRequest {
+        U2fSignRequest {
+            request_type: SIGN_REQUEST_TYPE.into(),
             app_id : self.app_id.clone(),
-            challenge: encode_config(challenge.challenge.as_bytes(), URL_SAFE_NO_PAD),
+            challenge: challenge.challenge,
+            timeout_seconds: self.timeout_seconds,
+            request_id: None,
             registered_keys: keys
-        };
-
-        signed_request
+        }
     }  
 
-    pub fn sign_response(&self, challenge: Challenge, reg: Registration, sign_resp: SignResponse, counter: u32) -> Result<u32> {
-        if expiration(challenge.timestamp) > Duration::seconds(300) {
-            return Err(U2fError::ChallengeExpired);
+    // Sign request in the format of the configured API version.
+    pub fn sign_message(&self, challenge: Challenge, registrations: Vec<Registration>) -> SignRequestMessage {
+        match self.api_version {
+            ApiVersion::V1_1 => SignRequestMessage::V1_1(self.sign_request(challenge, registrations)),
+            ApiVersion::V1_0 => SignRequestMessage::V1_0(U2fLegacySignRequest {
+                sign_requests: self.legacy_sign_requests(&challenge, registrations),
+                timeout_seconds: self.timeout_seconds,
+            }),
         }
+    }
+
+    fn legacy_sign_requests(&self, challenge: &Challenge, registrations: Vec<Registration>) -> Vec<SignRequest> {
+        registrations
+            .into_iter()
+            .map(|registration| SignRequest {
+                version: U2F_V2.into(),
+                challenge: challenge.challenge.clone(),
+                key_handle: get_encoded(&registration.key_handle[..]),
+                app_id: self.app_id.clone(),
+            })
+            .collect()
+    }
+
+    pub fn sign_response(&self, challenge: Challenge, reg: Registration, sign_resp: SignResponse, counter: u32) -> Result<Authorization> {
+        self.check_challenge(&challenge)?;
 
         if sign_resp.key_handle != get_encoded(&reg.key_handle[..]) {            
             return Err(U2fError::WrongKeyHandler);
@@ -125,22 +346,33 @@ impl U2f {
         let client_data: Vec<u8> = decode_config(&sign_resp.client_data[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidClientData)?;
         let sign_data: Vec<u8> = decode_config(&sign_resp.signature_data[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::InvalidSignatureData)?;
 
-        let public_key = reg.pub_key;
-
-        let auth = parse_sign_response(self.app_id.clone(), client_data.clone(), public_key, sign_data.clone());
-
-        match auth {
-            Ok(ref res) => {
-                // CounterTooLow is raised when the counter value received from the device is
-                // lower than last stored counter value.
-                if res.counter < counter {
-                    return Err(U2fError::CounterTooLow);
-                }
-                else {
-                    return Ok(res.counter);
-                }
-            },
-                Err(e) => return Err(e),
-            }
-        }       
-}
\ No newline at end of file
+        let mut auth = verify_sign_response(&*self.crypto, self.parsed_app_id()?, self.trusted_facets.as_deref(), &challenge.challenge, &client_data, &reg.pub_key, &sign_data)?;
+        self.consume_challenge(&challenge)?;
+
+        if !auth.user_presence && !self.allow_silent_authentication {
+            return Err(U2fError::InvalidUserPresenceByte);
+        }
+
+        // CounterTooLow is raised when the counter value received from the device does not
+        // satisfy the counter policy against the last stored counter value.
+        auth.possible_clone = self.counter_policy.check(counter, auth.counter)?;
+
+        Ok(auth)
+    }
+
+    // Verifies a sign response against whichever of `registrations` owns its key handle.
+    // `counters[i]` is the stored counter of `registrations[i]`.
+    pub fn sign_response_any(&self, challenge: Challenge, registrations: &[Registration], sign_resp: SignResponse, counters: &[u32]) -> Result<AuthenticatedRegistration> {
+        let key_handle: Vec<u8> = decode_config(&sign_resp.key_handle[..], URL_SAFE_NO_PAD).map_err(|_e| U2fError::WrongKeyHandler)?;
+
+        let index = registrations
+            .iter()
+            .position(|reg| reg.key_handle == key_handle)
+            .ok_or(U2fError::WrongKeyHandler)?;
+        let counter = *counters.get(index).ok_or(U2fError::MissingCounter)?;
+
+        let authorization = self.sign_response(challenge, registrations[index].clone(), sign_resp, counter)?;
+
+        Ok(AuthenticatedRegistration { index, authorization })
+    }
+}
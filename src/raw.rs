@@ -0,0 +1,313 @@
+// Raw U2F messages (CTAP1), framed as ISO 7816-4 APDUs.
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html
+
+use crate::crypto_provider::default_provider;
+use crate::u2ferror::U2fError;
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+pub const CLA: u8 = 0x00;
+
+pub const INS_REGISTER: u8 = 0x01;
+pub const INS_AUTHENTICATE: u8 = 0x02;
+pub const INS_VERSION: u8 = 0x03;
+
+pub const SW_NO_ERROR: u16 = 0x9000;
+pub const SW_WRONG_LENGTH: u16 = 0x6700;
+pub const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+pub const SW_WRONG_DATA: u16 = 0x6a80;
+pub const SW_INS_NOT_SUPPORTED: u16 = 0x6d00;
+pub const SW_CLA_NOT_SUPPORTED: u16 = 0x6e00;
+
+// Length of the challenge and application parameters (SHA-256 hashes).
+pub const PARAMETER_LENGTH: usize = 32;
+
+// Control byte (P1) of an authentication request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthenticateControl {
+    // Only check whether the key handle belongs to the token and application.
+    CheckOnly = 0x07,
+    EnforceUserPresence = 0x03,
+    DontEnforceUserPresence = 0x08,
+}
+
+impl AuthenticateControl {
+    pub fn from_byte(byte: u8) -> Result<AuthenticateControl> {
+        match byte {
+            0x07 => Ok(AuthenticateControl::CheckOnly),
+            0x03 => Ok(AuthenticateControl::EnforceUserPresence),
+            0x08 => Ok(AuthenticateControl::DontEnforceUserPresence),
+            _ => Err(U2fError::InvalidApdu),
+        }
+    }
+}
+
+// Length encoding of a command APDU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApduEncoding {
+    // One byte Lc and Le: at most 255 data bytes.
+    Short,
+    // Three byte Lc and two byte Le, as required by the U2F raw message spec.
+    Extended,
+}
+
+// A command APDU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Apdu {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+    // Maximum length of the expected response (Ne), `None` when no response data is expected.
+    pub ne: Option<usize>,
+}
+
+impl Apdu {
+    pub fn encode(&self, encoding: ApduEncoding) -> Result<Vec<u8>> {
+        let mut apdu = vec![self.cla, self.ins, self.p1, self.p2];
+
+        match encoding {
+            ApduEncoding::Short => {
+                if self.data.len() > 0xff || self.ne.is_some_and(|ne| ne > 0x100) {
+                    return Err(U2fError::InvalidApdu);
+                }
+
+                if !self.data.is_empty() {
+                    apdu.push(self.data.len() as u8);
+                    apdu.extend_from_slice(&self.data);
+                }
+                if let Some(ne) = self.ne {
+                    // 0x00 stands for 256.
+                    apdu.push(ne as u8);
+                }
+            }
+            ApduEncoding::Extended => {
+                if self.data.len() > 0xffff || self.ne.is_some_and(|ne| ne > 0x10000) {
+                    return Err(U2fError::InvalidApdu);
+                }
+
+                if !self.data.is_empty() {
+                    apdu.push(0x00);
+                    apdu.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+                    apdu.extend_from_slice(&self.data);
+                }
+                if let Some(ne) = self.ne {
+                    if self.data.is_empty() {
+                        apdu.push(0x00);
+                    }
+                    // 0x0000 stands for 65536.
+                    apdu.extend_from_slice(&(ne as u16).to_be_bytes());
+                }
+            }
+        }
+
+        Ok(apdu)
+    }
+
+    // Decodes a short or extended command APDU.
+    pub fn decode(apdu: &[u8]) -> Result<Apdu> {
+        if apdu.len() < 4 {
+            return Err(U2fError::InvalidApdu);
+        }
+
+        let (header, body) = apdu.split_at(4);
+        let (data, ne) = match body {
+            [] => (&body[..0], None),
+            [le] => (&body[..0], Some(short_ne(*le))),
+            [0x00, le1, le2] => (&body[..0], Some(extended_ne(*le1, *le2))),
+            [0x00, lc1, lc2, rest @ ..] => {
+                let lc = u16::from_be_bytes([*lc1, *lc2]) as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) if lc > 0 => (rest, None),
+                    Some(2) if lc > 0 => (&rest[..lc], Some(extended_ne(rest[lc], rest[lc + 1]))),
+                    _ => return Err(U2fError::InvalidApdu),
+                }
+            }
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) if lc > 0 => (rest, None),
+                    Some(1) if lc > 0 => (&rest[..lc], Some(short_ne(rest[lc]))),
+                    _ => return Err(U2fError::InvalidApdu),
+                }
+            }
+        };
+
+        Ok(Apdu {
+            cla: header[0],
+            ins: header[1],
+            p1: header[2],
+            p2: header[3],
+            data: data.to_vec(),
+            ne,
+        })
+    }
+}
+
+fn short_ne(le: u8) -> usize {
+    if le == 0 { 0x100 } else { le as usize }
+}
+
+fn extended_ne(le1: u8, le2: u8) -> usize {
+    match u16::from_be_bytes([le1, le2]) {
+        0 => 0x10000,
+        le => le as usize,
+    }
+}
+
+// U2F_REGISTER, U2F_AUTHENTICATE and U2F_VERSION requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Register {
+        challenge: [u8; PARAMETER_LENGTH],
+        application: [u8; PARAMETER_LENGTH],
+    },
+    Authenticate {
+        control: AuthenticateControl,
+        challenge: [u8; PARAMETER_LENGTH],
+        application: [u8; PARAMETER_LENGTH],
+        key_handle: Vec<u8>,
+    },
+    Version,
+}
+
+impl Request {
+    // Registration request for the client data that will be passed to `parse_registration`.
+    pub fn register(app_id: &str, client_data: &[u8]) -> Request {
+        let crypto = default_provider();
+
+        Request::Register {
+            challenge: crypto.sha256(client_data),
+            application: crypto.sha256(app_id.as_bytes()),
+        }
+    }
+
+    // Authentication request for the client data that will be passed to `parse_sign_response`.
+    pub fn authenticate(control: AuthenticateControl, app_id: &str, client_data: &[u8], key_handle: &[u8]) -> Request {
+        let crypto = default_provider();
+
+        Request::Authenticate {
+            control,
+            challenge: crypto.sha256(client_data),
+            application: crypto.sha256(app_id.as_bytes()),
+            key_handle: key_handle.to_vec(),
+        }
+    }
+
+    pub fn to_apdu(&self) -> Result<Apdu> {
+        let (ins, p1, data) = match self {
+            // Some tokens only register with the enforce-user-presence bit set.
+            Request::Register { challenge, application } => (INS_REGISTER, AuthenticateControl::EnforceUserPresence as u8, [&challenge[..], &application[..]].concat()),
+            Request::Authenticate { control, challenge, application, key_handle } => {
+                if key_handle.len() > 0xff {
+                    return Err(U2fError::InvalidApdu);
+                }
+                let data = [&challenge[..], &application[..], &[key_handle.len() as u8], &key_handle[..]].concat();
+                (INS_AUTHENTICATE, *control as u8, data)
+            }
+            Request::Version => (INS_VERSION, 0x00, vec![]),
+        };
+
+        Ok(Apdu { cla: CLA, ins, p1, p2: 0x00, data, ne: Some(0x10000) })
+    }
+
+    pub fn from_apdu(apdu: &Apdu) -> Result<Request> {
+        if apdu.cla != CLA {
+            return Err(U2fError::InvalidApdu);
+        }
+
+        let data = &apdu.data[..];
+        match apdu.ins {
+            INS_REGISTER => {
+                if data.len() != 2 * PARAMETER_LENGTH {
+                    return Err(U2fError::InvalidApdu);
+                }
+                let (challenge, application) = split_parameters(data);
+                Ok(Request::Register { challenge, application })
+            }
+            INS_AUTHENTICATE => {
+                if data.len() < 2 * PARAMETER_LENGTH + 1 {
+                    return Err(U2fError::InvalidApdu);
+                }
+                let (challenge, application) = split_parameters(data);
+                let key_handle_length = data[2 * PARAMETER_LENGTH] as usize;
+                let key_handle = &data[2 * PARAMETER_LENGTH + 1..];
+                if key_handle.len() != key_handle_length {
+                    return Err(U2fError::InvalidApdu);
+                }
+
+                Ok(Request::Authenticate {
+                    control: AuthenticateControl::from_byte(apdu.p1)?,
+                    challenge,
+                    application,
+                    key_handle: key_handle.to_vec(),
+                })
+            }
+            INS_VERSION if data.is_empty() => Ok(Request::Version),
+            _ => Err(U2fError::InvalidApdu),
+        }
+    }
+
+    pub fn encode(&self, encoding: ApduEncoding) -> Result<Vec<u8>> {
+        let mut apdu = self.to_apdu()?;
+        if encoding == ApduEncoding::Short {
+            apdu.ne = Some(0x100);
+        }
+        apdu.encode(encoding)
+    }
+
+    pub fn decode(apdu: &[u8]) -> Result<Request> {
+        Request::from_apdu(&Apdu::decode(apdu)?)
+    }
+}
+
+fn split_parameters(data: &[u8]) -> ([u8; PARAMETER_LENGTH], [u8; PARAMETER_LENGTH]) {
+    let mut challenge = [0u8; PARAMETER_LENGTH];
+    let mut application = [0u8; PARAMETER_LENGTH];
+    challenge.copy_from_slice(&data[..PARAMETER_LENGTH]);
+    application.copy_from_slice(&data[PARAMETER_LENGTH..2 * PARAMETER_LENGTH]);
+    (challenge, application)
+}
+
+// A response APDU: the response data followed by the status word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub data: Vec<u8>,
+    pub status: u16,
+}
+
+impl Response {
+    pub fn new(data: Vec<u8>, status: u16) -> Self {
+        Response { data, status }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [&self.data[..], &self.status.to_be_bytes()].concat()
+    }
+
+    pub fn decode(response: &[u8]) -> Result<Response> {
+        if response.len() < 2 {
+            return Err(U2fError::InvalidApdu);
+        }
+
+        let (data, status) = response.split_at(response.len() - 2);
+        Ok(Response { data: data.to_vec(), status: u16::from_be_bytes([status[0], status[1]]) })
+    }
+
+    // The response data if the status word is `SW_NO_ERROR`. For U2F_REGISTER this is the
+    // registration data taken by `parse_registration`, for U2F_AUTHENTICATE the signature
+    // data taken by `parse_sign_response`.
+    pub fn into_data(self) -> Result<Vec<u8>> {
+        match self.status {
+            SW_NO_ERROR => Ok(self.data),
+            status => Err(U2fError::ApduStatus(status)),
+        }
+    }
+
+    // The version string of a U2F_VERSION response, "U2F_V2" for current tokens.
+    pub fn version(self) -> Result<String> {
+        String::from_utf8(self.into_data()?).map_err(|_e| U2fError::InvalidApdu)
+    }
+}
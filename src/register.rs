@@ -1,79 +1,198 @@
 use bytes::{Bytes, BufMut};
-use openssl::sha::sha256;
+use chrono::prelude::*;
 use byteorder::{ByteOrder, BigEndian};
 
 use crate::util::*;
 use crate::messages::RegisteredKey;
+use crate::device::DeviceInfo;
 use crate::u2ferror::U2fError;
+use crate::crypto_provider::{default_provider, CryptoProvider};
 use std::convert::TryFrom;
+use serde::Deserialize;
 
 /// The `Result` type used in this crate.
 type Result<T> = ::std::result::Result<T, U2fError>;
 
+// Version written by `Registration`'s Serialize implementation.
+pub const REGISTRATION_STORAGE_VERSION: u32 = 1;
+
 // Single enrolment or pairing between an application and a token.
-#[derive(Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
+//
+// Serializes to the versioned storage format (`RegistrationV1`). Deserialize also
+// accepts the unversioned format of earlier releases, where byte fields were arrays
+// of integers, so stored registrations migrate on their next write.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(into = "RegistrationV1", try_from = "serde_json::Value")]
 pub struct Registration {
     pub key_handle: Vec<u8>,
     pub pub_key: Vec<u8>,
 
     // AttestationCert can be null for Authenticate requests.
     pub attestation_cert: Option<Vec<u8>>,
+
+    pub metadata: RegistrationMetadata,
+}
+
+// Bookkeeping stored alongside a registration.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationMetadata {
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub counter: u32,
+    pub nickname: Option<String>,
+}
+
+// Storage format, version 1: byte fields as unpadded base64url strings.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegistrationV1 {
+    version: u32,
+    #[serde(with = "base64url")]
+    key_handle: Vec<u8>,
+    #[serde(with = "base64url")]
+    pub_key: Vec<u8>,
+    #[serde(with = "base64url_option", default)]
+    attestation_cert: Option<Vec<u8>>,
+    #[serde(flatten)]
+    metadata: RegistrationMetadata,
+}
+
+// Unversioned format written by earlier releases: byte fields as arrays of integers.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LegacyRegistration {
+    key_handle: Vec<u8>,
+    pub_key: Vec<u8>,
+    attestation_cert: Option<Vec<u8>>,
+}
+
+impl From<Registration> for RegistrationV1 {
+    fn from(reg: Registration) -> Self {
+        RegistrationV1 {
+            version: REGISTRATION_STORAGE_VERSION,
+            key_handle: reg.key_handle,
+            pub_key: reg.pub_key,
+            attestation_cert: reg.attestation_cert,
+            metadata: reg.metadata,
+        }
+    }
+}
+
+// Decodes the current format first and falls back to the legacy one. When neither
+// matches, the error of the current format is reported, as it names what is wrong
+// with a registration written by this release.
+impl TryFrom<serde_json::Value> for Registration {
+    type Error = String;
+
+    fn try_from(stored: serde_json::Value) -> std::result::Result<Self, Self::Error> {
+        let v1_error = match RegistrationV1::deserialize(&stored) {
+            Ok(reg) => {
+                if reg.version != REGISTRATION_STORAGE_VERSION {
+                    return Err(format!("unsupported registration version {}", reg.version));
+                }
+
+                return Ok(Registration {
+                    key_handle: reg.key_handle,
+                    pub_key: reg.pub_key,
+                    attestation_cert: reg.attestation_cert,
+                    metadata: reg.metadata,
+                });
+            }
+            Err(e) => e,
+        };
+
+        match LegacyRegistration::deserialize(&stored) {
+            Ok(reg) => Ok(Registration {
+                key_handle: reg.key_handle,
+                pub_key: reg.pub_key,
+                attestation_cert: reg.attestation_cert,
+                metadata: RegistrationMetadata::default(),
+            }),
+            Err(_e) => Err(v1_error.to_string()),
+        }
+    }
 }
 
 impl Registration {
+    // Records a successful authentication: stores the new counter and the time of use.
+    pub fn record_use(&mut self, counter: u32, at: DateTime<Utc>) {
+        self.metadata.counter = counter;
+        self.metadata.last_used = Some(at);
+    }
+
     pub fn subject(&self) -> Option<String> {
-        let cert = match self.attestation_cert.as_ref() {
-            Some(cert) => cert,
-            None => return None,
-        };
+        let cert = self.attestation_cert.as_ref()?;
 
-        super::crypto::X509PublicKey::try_from(cert.as_slice()).map(|cert|{
-            cert.subject_name()
-        }).unwrap_or(None)
+        default_provider().parse_certificate(cert).ok()?.subject
     }
 
     pub fn issuer(&self) -> Option<String> {
-        let cert = match self.attestation_cert.as_ref() {
-            Some(cert) => cert,
-            None => return None,
-        };
+        let cert = self.attestation_cert.as_ref()?;
+
+        default_provider().parse_certificate(cert).ok()?.issuer
+    }
+
+    // Structured details about the authenticator, decoded from the attestation certificate.
+    pub fn device_info(&self) -> Option<DeviceInfo> {
+        let cert = self.attestation_cert.as_ref()?;
 
-        super::crypto::X509PublicKey::try_from(cert.as_slice()).map(|cert|{
-            cert.issuer_name()
-        }).unwrap_or(None)
+        DeviceInfo::from_certificate(cert).ok()
     }
 }
 
 pub fn parse_registration(app_id: String, client_data: Vec<u8>, registration_data: Vec<u8>) -> Result<Registration> {
-    let reserved_byte = registration_data[0];
-    if reserved_byte != 0x05 {
-        return Err(U2fError::InvalidReservedByte);
-    }
+    parse_registration_with(&*default_provider(), app_id, client_data, registration_data)
+}
 
+// `parse_registration` on the given crypto backend.
+pub fn parse_registration_with(crypto: &dyn CryptoProvider, app_id: String, client_data: Vec<u8>, registration_data: Vec<u8>) -> Result<Registration> {
     let mut mem = Bytes::from(registration_data);
-    
+
+    if mem.is_empty() {
+        return Err(U2fError::InvalidRegistrationData);
+    }
+
     //Start parsing ... advance the reserved byte.
-    let _ = mem.split_to(1);
+    let reserved_byte = mem.split_to(1);
+    if reserved_byte[0] != 0x05 {
+        return Err(U2fError::InvalidReservedByte);
+    }
 
     // P-256 NIST elliptic curve
+    if mem.len() < 65 {
+        return Err(U2fError::TruncatedPublicKey);
+    }
     let public_key = mem.split_to(65);
 
     // Key Handle
+    if mem.is_empty() {
+        return Err(U2fError::TruncatedKeyHandle);
+    }
     let key_handle_size = mem.split_to(1);
-    let key_len = BigEndian::read_uint(&key_handle_size[..], 1);
-    let key_handle = mem.split_to(key_len as usize);
+    let key_len = BigEndian::read_uint(&key_handle_size[..], 1) as usize;
+    if mem.len() < key_len {
+        return Err(U2fError::TruncatedKeyHandle);
+    }
+    let key_handle = mem.split_to(key_len);
 
     // The certificate length needs to be inferred by parsing.
-    let cert_len = asn_length(mem.clone()).unwrap();
+    let cert_len = asn_length(mem.clone()).map_err(|_e| U2fError::InvalidCertificateLength)?;
+    if mem.len() < cert_len {
+        return Err(U2fError::InvalidCertificateLength);
+    }
     let attestation_certificate = mem.split_to(cert_len);
 
     // Remaining data corresponds to the signature 
+    if mem.is_empty() {
+        return Err(U2fError::MissingSignature);
+    }
     let signature = mem;
 
     // Let's build the msg to verify the signature
-    let app_id_hash = sha256(&app_id.into_bytes());
-    let client_data_hash = sha256(&client_data[..]);
+    let app_id_hash = crypto.sha256(&app_id.into_bytes());
+    let client_data_hash = crypto.sha256(&client_data[..]);
 
     let mut msg = vec![0x00]; // A byte reserved for future use [1 byte] with the value 0x00
     msg.put(app_id_hash.as_ref());
@@ -84,13 +203,10 @@ pub fn parse_registration(app_id: String, client_data: Vec<u8>, registration_dat
 
     // The signature is to be verified by the relying party using the public key certified
     // in the attestation certificate.
-    let cerificate_public_key = super::crypto::X509PublicKey::try_from(&attestation_certificate[..])?;
-
-    if !(cerificate_public_key.is_secp256r1()?) {
-        return Err(U2fError::BadCertificate);
-    }
+    let certificate = crypto.parse_certificate(&attestation_certificate[..])?;
+    let certificate_public_key = certificate.p256_public_key.ok_or(U2fError::BadCertificate)?;
 
-    let verified = cerificate_public_key.verify_signature(&signature[..], &msg[..])?;
+    let verified = crypto.verify_p256(&certificate_public_key, &msg[..], &signature[..])?;
 
     if !verified {
         return Err(U2fError::BadCertificate);
@@ -100,6 +216,7 @@ pub fn parse_registration(app_id: String, client_data: Vec<u8>, registration_dat
         key_handle: key_handle[..].to_vec(),
         pub_key: public_key[..].to_vec(), 
         attestation_cert: Some(attestation_certificate[..].to_vec()),
+        metadata: RegistrationMetadata::default(),
     };
 
     Ok(registration)
@@ -107,8 +224,9 @@ pub fn parse_registration(app_id: String, client_data: Vec<u8>, registration_dat
 
 pub fn get_registered_key(app_id: String, key_handle: Vec<u8>) -> RegisteredKey {
     RegisteredKey {
-        app_id: app_id,
+        app_id,
         version: U2F_V2.into(),
-        key_handle: Some(get_encoded(key_handle.as_slice()))
+        key_handle: Some(get_encoded(key_handle.as_slice())),
+        transports: None,
     }
 }
\ No newline at end of file
@@ -0,0 +1,307 @@
+// Virtual U2F authenticator answering JS API requests, for tests and development.
+// It plays both the browser (client data) and the token (raw register/authenticate).
+// Like hardware tokens it keeps no per-credential state: private keys travel in the
+// key handles, sealed by a `KeyWrapper`.
+
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::appid::Origin;
+use crate::crypto_provider::{default_provider, CryptoProvider};
+use crate::der;
+use crate::key_wrapper::{KeyWrapper, PRIVATE_KEY_LENGTH};
+use crate::messages::{ClientData, RegisterResponse, SignResponse, U2fRegisterRequest, U2fSignRequest};
+use crate::u2ferror::U2fError;
+use crate::util::{get_encoded, REGISTER_TYPE, SIGN_TYPE, U2F_V2};
+
+/// The `Result` type used in this crate.
+type Result<T> = ::std::result::Result<T, U2fError>;
+
+const REGISTRATION_RESERVED_BYTE: u8 = 0x05;
+const USER_PRESENCE_FLAG: u8 = 0x01;
+const SUBJECT: &str = "Soft U2F Token";
+
+// AlgorithmIdentifier of ecdsa-with-SHA256.
+const ECDSA_WITH_SHA256: &[u8] = &[0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+// SubjectPublicKeyInfo of a P-256 key, up to the uncompressed point.
+const P256_PUBLIC_KEY_INFO: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce,
+    0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+pub struct SoftToken {
+    attestation_cert: Vec<u8>,
+    attestation_key: Vec<u8>,
+    counter: u32,
+    user_presence: bool,
+    key_wrapper: KeyWrapper,
+    facet_id: Option<String>,
+    crypto: Arc<dyn CryptoProvider>,
+}
+
+impl SoftToken {
+    // A token with a freshly generated, self-signed attestation certificate and wrapping key.
+    pub fn new() -> Result<Self> {
+        let crypto = default_provider();
+        let attestation_key = crypto.generate_p256_key()?;
+        let public_key = crypto.p256_public_key(&attestation_key)?;
+        let attestation_cert = certificate(&*crypto, SUBJECT, &public_key, SUBJECT, &attestation_key, &[])?;
+
+        Ok(SoftToken {
+            attestation_cert,
+            attestation_key,
+            counter: 0,
+            user_presence: true,
+            key_wrapper: KeyWrapper::generate()?,
+            facet_id: None,
+            crypto,
+        })
+    }
+
+    // Signs registrations with the DER encoded certificate and its P-256 private key, DER
+    // encoded in SEC1 or PKCS#8 form.
+    pub fn with_attestation(mut self, cert: &[u8], private_key: &[u8]) -> Result<Self> {
+        let private_key = private_key_from_der(private_key)?;
+        self.crypto.p256_public_key(&private_key)?;
+
+        self.attestation_key = private_key;
+        self.attestation_cert = cert.to_vec();
+        Ok(self)
+    }
+
+    // Generates, signs and wraps keys with `crypto` instead of the default provider.
+    pub fn with_crypto_provider(mut self, crypto: Arc<dyn CryptoProvider>) -> Self {
+        self.key_wrapper = self.key_wrapper.with_crypto_provider(crypto.clone());
+        self.crypto = crypto;
+        self
+    }
+
+    // Seals key handles with `key_wrapper`, so that several instances sharing it accept
+    // each other's registrations.
+    pub fn with_key_wrapper(mut self, key_wrapper: KeyWrapper) -> Self {
+        self.key_wrapper = key_wrapper;
+        self
+    }
+
+    // Origin put in the client data, as a browser on another facet of the app ID would.
+    // Defaults to the origin of the app ID.
+    pub fn with_facet_id(mut self, facet_id: &str) -> Self {
+        self.facet_id = Some(facet_id.to_string());
+        self
+    }
+
+    // Value of the signature counter before the next authentication.
+    pub fn with_counter(mut self, counter: u32) -> Self {
+        self.counter = counter;
+        self
+    }
+
+    // Whether authentications report a touch. Defaults to true.
+    pub fn with_user_presence(mut self, present: bool) -> Self {
+        self.user_presence = present;
+        self
+    }
+
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    pub fn attestation_cert(&self) -> &[u8] {
+        &self.attestation_cert
+    }
+
+    // Registers a new key for the request's app ID. Fails with `DeviceIneligible` when one
+    // of the `registered_keys` already belongs to this token.
+    pub fn register(&mut self, request: &U2fRegisterRequest) -> Result<RegisterResponse> {
+        let register_request = request
+            .register_requests
+            .iter()
+            .find(|r| r.version == U2F_V2)
+            .ok_or(U2fError::DeviceIneligible)?;
+
+        for key in &request.registered_keys {
+            if self.find_key(key.key_handle.as_ref(), &key.app_id).is_some() {
+                return Err(U2fError::DeviceIneligible);
+            }
+        }
+
+        let client_data = client_data(REGISTER_TYPE, &register_request.challenge, &self.origin(&request.app_id))?;
+        let application = self.crypto.sha256(request.app_id.as_bytes());
+
+        let private_key = self.crypto.generate_p256_key()?;
+        let public_key = self.crypto.p256_public_key(&private_key)?;
+        let key_handle = self.key_wrapper.wrap(&application, &private_key)?;
+
+        let mut msg = vec![0x00];
+        msg.extend_from_slice(&application);
+        msg.extend_from_slice(&self.crypto.sha256(&client_data));
+        msg.extend_from_slice(&key_handle);
+        msg.extend_from_slice(&public_key);
+
+        let mut registration_data = vec![REGISTRATION_RESERVED_BYTE];
+        registration_data.extend_from_slice(&public_key);
+        registration_data.push(key_handle.len() as u8);
+        registration_data.extend_from_slice(&key_handle);
+        registration_data.extend_from_slice(&self.attestation_cert);
+        registration_data.extend(self.crypto.sign_p256(&self.attestation_key, &msg)?);
+
+        Ok(RegisterResponse {
+            registration_data: get_encoded(&registration_data),
+            version: U2F_V2.into(),
+            client_data: get_encoded(&client_data),
+        })
+    }
+
+    // Signs the challenge with the first of the `registered_keys` held by this token.
+    pub fn sign(&mut self, request: &U2fSignRequest) -> Result<SignResponse> {
+        let (key_handle, application, key) = request
+            .registered_keys
+            .iter()
+            .filter_map(|registered| {
+                let app_id = if registered.app_id.is_empty() { &request.app_id } else { &registered.app_id };
+                self.find_key(registered.key_handle.as_ref(), app_id)
+            })
+            .next()
+            .ok_or(U2fError::DeviceIneligible)?;
+
+        let client_data = client_data(SIGN_TYPE, &request.challenge, &self.origin(&request.app_id))?;
+
+        self.counter = self.counter.wrapping_add(1);
+        let flags = if self.user_presence { USER_PRESENCE_FLAG } else { 0x00 };
+
+        let mut msg = application.to_vec();
+        msg.push(flags);
+        msg.extend_from_slice(&self.counter.to_be_bytes());
+        msg.extend_from_slice(&self.crypto.sha256(&client_data));
+
+        let mut signature_data = vec![flags];
+        signature_data.extend_from_slice(&self.counter.to_be_bytes());
+        signature_data.extend(self.crypto.sign_p256(&key, &msg)?);
+
+        Ok(SignResponse {
+            key_handle: get_encoded(&key_handle),
+            signature_data: get_encoded(&signature_data),
+            client_data: get_encoded(&client_data),
+        })
+    }
+
+    // The key sealed in the base64url key handle, if it was issued for `app_id`.
+    fn find_key(&self, key_handle: Option<&String>, app_id: &str) -> Option<(Vec<u8>, [u8; 32], Vec<u8>)> {
+        let key_handle = base64::decode_config(key_handle?, base64::URL_SAFE_NO_PAD).ok()?;
+        let application = self.crypto.sha256(app_id.as_bytes());
+
+        let private_key = self.key_wrapper.unwrap(&application, &key_handle).ok()?;
+
+        Some((key_handle, application, private_key))
+    }
+
+    fn origin(&self, app_id: &str) -> String {
+        match &self.facet_id {
+            Some(facet_id) => facet_id.clone(),
+            None => Origin::parse(app_id).map(|origin| origin.to_string()).unwrap_or_else(|_e| app_id.to_string()),
+        }
+    }
+}
+
+// Client data as a browser serving `origin` would build it.
+fn client_data(typ: &str, challenge: &str, origin: &str) -> Result<Vec<u8>> {
+    let client_data = ClientData {
+        typ: typ.into(),
+        challenge: challenge.into(),
+        origin: origin.into(),
+        cid_pubkey: None,
+    };
+
+    serde_json::to_vec(&client_data).map_err(|_e| U2fError::InvalidClientData)
+}
+
+// The scalar of a DER encoded P-256 private key: an ECPrivateKey (SEC1), possibly wrapped
+// in a PrivateKeyInfo (PKCS#8).
+fn private_key_from_der(private_key: &[u8]) -> Result<Vec<u8>> {
+    let (fields, _) = der::expect(private_key, der::SEQUENCE).map_err(|_e| U2fError::InvalidPrivateKey)?;
+    let (version, fields) = der::expect(fields, der::INTEGER).map_err(|_e| U2fError::InvalidPrivateKey)?;
+
+    let scalar = match version {
+        [0] => {
+            let (_, fields) = der::expect(fields, der::SEQUENCE).map_err(|_e| U2fError::InvalidPrivateKey)?;
+            let (key, _) = der::expect(fields, der::OCTET_STRING).map_err(|_e| U2fError::InvalidPrivateKey)?;
+            return private_key_from_der(key);
+        }
+        [1] => der::expect(fields, der::OCTET_STRING).map_err(|_e| U2fError::InvalidPrivateKey)?.0,
+        _ => return Err(U2fError::InvalidPrivateKey),
+    };
+
+    if scalar.len() > PRIVATE_KEY_LENGTH {
+        return Err(U2fError::InvalidPrivateKey);
+    }
+
+    let mut private_key = vec![0u8; PRIVATE_KEY_LENGTH - scalar.len()];
+    private_key.extend_from_slice(scalar);
+    Ok(private_key)
+}
+
+// A DER encoded v3 certificate for the P-256 `public_key` of `subject`, valid for ten years
+// and signed with ecdsa-with-SHA256 by `issuer_key`. Names carry a single common name;
+// `extensions` are encoded with `extension`.
+pub(crate) fn certificate(
+    crypto: &dyn CryptoProvider,
+    subject: &str,
+    public_key: &[u8],
+    issuer: &str,
+    issuer_key: &[u8],
+    extensions: &[Vec<u8>],
+) -> Result<Vec<u8>> {
+    if public_key.len() != 65 {
+        return Err(U2fError::InvalidPublicKey);
+    }
+
+    let mut serial_number = [0u8; 8];
+    crypto.random_bytes(&mut serial_number)?;
+
+    let now = Utc::now();
+    let validity = der::sequence(&[&utc_time(now), &utc_time(now + Duration::days(3650))]);
+
+    let mut fields = vec![
+        der::tlv(der::VERSION, &der::integer(&[2])),
+        der::integer(&serial_number),
+        ECDSA_WITH_SHA256.to_vec(),
+        name(issuer),
+        validity,
+        name(subject),
+        [P256_PUBLIC_KEY_INFO, public_key].concat(),
+    ];
+    if !extensions.is_empty() {
+        fields.push(der::tlv(der::EXTENSIONS, &der::tlv(der::SEQUENCE, &extensions.concat())));
+    }
+
+    let tbs = der::tlv(der::SEQUENCE, &fields.concat());
+    let signature = crypto.sign_p256(issuer_key, &tbs)?;
+
+    Ok(der::sequence(&[&tbs, ECDSA_WITH_SHA256, &der::tlv(der::BIT_STRING, &[&[0], &signature[..]].concat())]))
+}
+
+// A certificate extension, `oid` being the content of its OBJECT IDENTIFIER and `value`
+// the DER encoded extnValue. Only the tests issue certificates with extensions.
+#[cfg(test)]
+pub(crate) fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let oid = der::tlv(der::OID, oid);
+    let value = der::tlv(der::OCTET_STRING, value);
+
+    if critical {
+        der::sequence(&[&oid, &der::tlv(der::BOOLEAN, &[0xff]), &value])
+    } else {
+        der::sequence(&[&oid, &value])
+    }
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    let attribute = der::sequence(&[&der::tlv(der::OID, COMMON_NAME), &der::tlv(der::UTF8_STRING, common_name.as_bytes())]);
+    der::sequence(&[&der::tlv(der::SET, &attribute)])
+}
+
+fn utc_time(time: DateTime<Utc>) -> Vec<u8> {
+    der::tlv(der::UTC_TIME, time.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+}
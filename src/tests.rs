@@ -1,35 +1,83 @@
 use crate::protocol::{U2f, Challenge};
-use crate::messages::{RegisterResponse, RegisterRequest, SignResponse};
-use crate::register::Registration;
+use crate::messages::{ApiVersion, ClientData, Facet, TrustedFacetsBuilder, TrustedFacetsList, ErrorResponse, RegisterRequestMessage, RegisterResponse, RegisterRequest, SignRequestMessage, SignResponse, SignResponseData, U2fSignRequest, U2fSignResponse};
+use crate::register::{Registration, RegistrationMetadata, parse_registration};
+use crate::u2ferror::U2fError;
+use crate::clock::Clock;
+use crate::attestation::{AttestationPolicy, AttestationTrustStore};
+use crate::metadata::{AuthenticatorStatus, MetadataService};
+use crate::device::{DeviceInfo, Transport};
+use crate::authorization::{CounterPolicy, parse_sign_response};
+use crate::raw::{self, Apdu, ApduEncoding, AuthenticateControl, Request, Response};
+use crate::hid::{self, Command, Decoder, InitResponse, Message};
+use crate::ble;
+use crate::nfc::{self, NfcSession};
+use crate::soft_token::{self, SoftToken};
+use crate::key_wrapper::{self, KeyWrapper};
+use crate::appid::{effective_domain, is_public_suffix, AppId};
+use crate::crypto_provider::{default_provider, CryptoProvider};
+#[cfg(feature = "openssl")]
+use crate::crypto_provider::OpenSslProvider;
+#[cfg(feature = "rust-crypto")]
+use crate::crypto_provider::RustCryptoProvider;
+use crate::der;
+use crate::challenge_store::{ChallengeStore, FileChallengeStore, MemoryChallengeStore};
+use std::sync::Arc;
+
+// Every crypto backend compiled in, so that the test vectors run against each of them.
+#[allow(clippy::vec_init_then_push)]
+fn crypto_providers() -> Vec<Arc<dyn CryptoProvider>> {
+    let mut providers: Vec<Arc<dyn CryptoProvider>> = vec![];
+    #[cfg(feature = "openssl")]
+    providers.push(Arc::new(OpenSslProvider));
+    #[cfg(feature = "rust-crypto")]
+    providers.push(Arc::new(RustCryptoProvider));
+    providers
+}
+
+// Registration data of a Krypton token for https://u2f.bin.coffee, with a self-signed attestation certificate.
+const KRYPTON_REGISTRATION_DATA: &str = "BQS53KgoebC9HkJSbZM2r7C9oOnEysjR06iSnglpQIs6KeaCFwKQx6XbmrM2-p9BbdNOPvhF0GtUwNp7g7HznOIUUCzlyN8X4i7yD9ODA_0tmZjg1CmSI9If20U86SgMBqrcrK0radduqslZczEtivFMKXaaeqMT2rs7jfMb124XtnCwp4u5lCWVLYWMhmKyPlraMIIBJzCBzqADAgECAgF7MAoGCCqGSM49BAMCMBYxFDASBgNVBAMMC0tyeXB0b24gS2V5MB4XDTIwMDEyNTIyNTMyOVoXDTMwMDEyNTEwNTMyOVowFjEUMBIGA1UEAwwLS3J5cHRvbiBLZXkwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAS53KgoebC9HkJSbZM2r7C9oOnEysjR06iSnglpQIs6KeaCFwKQx6XbmrM2-p9BbdNOPvhF0GtUwNp7g7HznOIUow0wCzAJBgNVHRMEAjAAMAoGCCqGSM49BAMCA0gAMEUCIQDeU4DwRJV_CAcormHMaYBYeTkFNQuUQsK77PF7jzy14QIgfXP5iop-DQqQjVkJUD11WeRvKCqZWRhyleQcRmsj584wRAIgf4vqsRgB6azPwVGGG6EDx4ioThOyLEfo8GPHWe7Pva8CIBE9P0-RlFgVOPQZFGlFWtzqzIy-3l4BkmIYgpSILlYt";
 
 fn verify_register(app_id: &str, req: &str, resp: &str) -> Registration {
     let reg:RegisterRequest = serde_json::from_str(req).unwrap();
     let resp:RegisterResponse = serde_json::from_str(resp).unwrap();
 
-    let u2f = U2f::new(app_id.to_string());
-
     let challenge = Challenge {
         app_id: app_id.to_string(),
         challenge: reg.challenge,
         timestamp: format!("{:?}", chrono::Utc::now()),
     };
 
-    u2f.register_response(challenge, resp).unwrap()
+    let mut registrations = vec![];
+    for crypto in crypto_providers() {
+        let u2f = U2f::new(app_id.to_string()).with_crypto_provider(crypto);
+        match u2f.register_response(challenge.clone(), resp.clone()) {
+            Ok(reg) => registrations.push(reg),
+            Err(e) => panic!("registration {} rejected: {:?}", registrations.len(), e),
+        }
+    }
+
+    // Every backend extracts the same key.
+    for reg in &registrations[1..] {
+        assert_eq!((&reg.key_handle, &reg.pub_key), (&registrations[0].key_handle, &registrations[0].pub_key));
+    }
+
+    registrations.pop().unwrap()
 }
 
 fn verify_auth(app_id: &str, reg: Registration, challenge: String, resp: &str) {
 
     let resp:SignResponse = serde_json::from_str(resp).unwrap();
 
-    let u2f = U2f::new(app_id.to_string());
-
     let challenge = Challenge {
         app_id: app_id.to_string(),
         challenge,
         timestamp: format!("{:?}", chrono::Utc::now()),
     };
 
-    let _ = u2f.sign_response(challenge, reg, resp, 0).unwrap();
+    for crypto in crypto_providers() {
+        let u2f = U2f::new(app_id.to_string()).with_crypto_provider(crypto);
+        let _ = u2f.sign_response(challenge.clone(), reg.clone(), resp.clone(), 0).unwrap();
+    }
 }
 
 #[test]
@@ -60,6 +108,15 @@ fn test_verify_register() {
     assert_eq!(reg.subject().as_ref().unwrap(), "Yubico U2F EE Serial 250569226176");
     assert_eq!(reg.issuer().as_ref().unwrap(), "Yubico U2F Root CA Serial 457200631");
 
+    let device = reg.device_info().unwrap();
+    assert_eq!(device.to_string(), "YubiKey 4 (USB)");
+    assert_eq!(device.transports, vec![Transport::Usb]);
+    assert_eq!(device.yubico_device_id.as_ref().unwrap(), "1.3.6.1.4.1.41482.1.5");
+    assert_eq!(device.serial_number.as_ref().unwrap(), "5716F7C0");
+    assert_eq!(device.signature_algorithm.as_ref().unwrap(), "sha256WithRSAEncryption");
+    assert_eq!(device.not_before.unwrap(), "2014-08-01T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap());
+    assert_eq!(device.not_after.unwrap(), "2050-09-04T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap());
+
     let reg = r#"{"version": "U2F_V2","challenge": "x2ihLZaIcGhA-ByY2mgLc8aofEM"}"#;
     let resp = r#"
     {
@@ -101,3 +158,1442 @@ fn test_verify_auth() {
 
     verify_auth(app_id, reg, challenge.to_string(), resp);
 }
+
+fn register_error(app_id: &str, challenge: &str, client_data: &str) -> U2fError {
+    let resp = RegisterResponse {
+        registration_data: String::new(),
+        version: "U2F_V2".to_string(),
+        client_data: base64::encode_config(client_data.as_bytes(), base64::URL_SAFE_NO_PAD),
+    };
+
+    let u2f = U2f::new(app_id.to_string());
+
+    let challenge = Challenge {
+        app_id: app_id.to_string(),
+        challenge: challenge.to_string(),
+        timestamp: format!("{:?}", chrono::Utc::now()),
+    };
+
+    u2f.register_response(challenge, resp).err().unwrap()
+}
+
+#[test]
+fn test_register_client_data_mismatch() {
+    let app_id = "https://u2f.bin.coffee";
+    let client_data = r#"{"challenge":"6P5JxkcBo1n7MkYedNHMfasfv2U","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+
+    match register_error(app_id, "LA9qqMYT7snzJkc_EVPiwdnOJpQ", client_data) {
+        U2fError::ChallengeMismatch => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    match register_error("https://evil.example", "6P5JxkcBo1n7MkYedNHMfasfv2U", client_data) {
+        U2fError::OriginMismatch => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    let client_data = r#"{"challenge":"6P5JxkcBo1n7MkYedNHMfasfv2U","origin":"https://u2f.bin.coffee","typ":"navigator.id.getAssertion"}"#;
+    match register_error(app_id, "6P5JxkcBo1n7MkYedNHMfasfv2U", client_data) {
+        U2fError::InvalidClientDataType => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    match register_error(app_id, "6P5JxkcBo1n7MkYedNHMfasfv2U", "not json") {
+        U2fError::InvalidClientData => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn test_client_data_channel_id() {
+    // Client data example from the raw message formats specification.
+    let json = r#"{"typ":"navigator.id.finishEnrollment","challenge":"vqrS6WXDe1JUs5_c3i4-LkKIHRr-3XVb3azuA5TifHo","cid_pubkey":{"kty":"EC","crv":"P-256","x":"HzQwlfXX7Q4S5MtCCnZUNBw3RMzPO9tOyWjBqRl4tJ8","y":"XVguGFLIZx1fXg3wNqfdbn75hi4-_7-BxhMljw42Ht4"},"origin":"http://example.com"}"#;
+
+    let client_data = ClientData::from_bytes(json.as_bytes()).unwrap();
+    let cid_pubkey = client_data.cid_pubkey.as_ref().unwrap();
+    assert_eq!(cid_pubkey["crv"], "P-256");
+    assert_eq!(client_data.origin, "http://example.com");
+
+    let serialized: serde_json::Value = serde_json::to_value(&client_data).unwrap();
+    assert_eq!(&serialized["cid_pubkey"], cid_pubkey);
+    assert!(serialized.get("cidPubkey").is_none());
+}
+
+#[test]
+fn test_sign_client_data_mismatch() {
+    let app_id = "https://u2f.bin.coffee";
+
+    let reg = Registration {
+        key_handle: base64::decode_config("LOXI3xfiLvIP04MD_S2ZmB9zhg_d-dk39ZzzcpSr4juM1cRB8BFdfUMX2sOseJel_PdaEAYWKCWfyEmFP9OzbxwewTmUa26Q-VW_xjrbeSs", base64::URL_SAFE_NO_PAD).unwrap(),
+        pub_key: base64::decode_config("BL76bCsU_MWyiJlgOoX-WvsxFrKtDx3mq8jekbg_U1vDOxzWnsTDsBnd88YyJFPKnrGU5UxBhNR970LSAtiNCFo", base64::URL_SAFE_NO_PAD).unwrap(),
+        attestation_cert: None,
+        metadata: Default::default(),
+    };
+
+    let resp: SignResponse = serde_json::from_str(r#"
+    {
+        "clientData": "eyJjaGFsbGVuZ2UiOiJiUkxoMGZ4dTNEdk1yNXdzMnlsbW5RIiwib3JpZ2luIjoiaHR0cHM6Ly91MmYuYmluLmNvZmZlZSIsInR5cCI6Im5hdmlnYXRvci5pZC5nZXRBc3NlcnRpb24ifQ",
+        "keyHandle": "LOXI3xfiLvIP04MD_S2ZmB9zhg_d-dk39ZzzcpSr4juM1cRB8BFdfUMX2sOseJel_PdaEAYWKCWfyEmFP9OzbxwewTmUa26Q-VW_xjrbeSs",
+        "signatureData": "AQAAAAEwRAIgU4Kemc0A6fmxygmqe34NvBk2d4Fqy-kGN-RYV50jXK4CIFTb_fs1TA0grsffUInWmUdi94EPlqeK800KzdJY-iwH"
+    }
+    "#).unwrap();
+
+    let sign = |app_id: &str, challenge: &str| {
+        let challenge = Challenge {
+            app_id: app_id.to_string(),
+            challenge: challenge.to_string(),
+            timestamp: format!("{:?}", chrono::Utc::now()),
+        };
+        U2f::new(app_id.to_string()).sign_response(challenge, reg.clone(), resp.clone(), 0)
+    };
+
+    assert_eq!(sign(app_id, "bRLh0fxu3DvMr5ws2ylmnQ").unwrap().counter, 1);
+
+    match sign(app_id, "mjvdwudayivfuRrtTtxvej9BuGg") {
+        Err(U2fError::ChallengeMismatch) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    match sign("https://evil.example", "bRLh0fxu3DvMr5ws2ylmnQ") {
+        Err(U2fError::OriginMismatch) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+struct FixedClock(std::sync::Mutex<chrono::DateTime<chrono::Utc>>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn test_challenge_lifetime() {
+    let app_id = "https://u2f.bin.coffee";
+    let start = "2020-01-01T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    let clock = std::sync::Arc::new(FixedClock(std::sync::Mutex::new(start)));
+
+    let u2f = U2f::new(app_id.to_string())
+        .with_challenge_ttl(chrono::Duration::seconds(600))
+        .with_clock_skew(chrono::Duration::seconds(30))
+        .with_clock(clock.clone());
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let reg = Registration { key_handle: vec![1], pub_key: vec![], attestation_cert: None, metadata: Default::default() };
+    let resp = SignResponse { key_handle: String::new(), signature_data: String::new(), client_data: String::new() };
+    let sign = |now| {
+        *clock.0.lock().unwrap() = now;
+        u2f.sign_response(challenge.clone(), reg.clone(), resp.clone(), 0).err().unwrap()
+    };
+
+    // Within the window the challenge is accepted and verification moves on to the key handle.
+    match sign(start + chrono::Duration::seconds(599)) {
+        U2fError::ChallengeExpired | U2fError::ChallengeNotYetValid => panic!("challenge should be fresh"),
+        _ => (),
+    }
+
+    match sign(start + chrono::Duration::seconds(601)) {
+        U2fError::ChallengeExpired => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    match sign(start - chrono::Duration::seconds(20)) {
+        U2fError::ChallengeExpired | U2fError::ChallengeNotYetValid => panic!("challenge should be within skew"),
+        _ => (),
+    }
+
+    match sign(start - chrono::Duration::seconds(31)) {
+        U2fError::ChallengeNotYetValid => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn test_parse_registration_truncated() {
+    let app_id = "https://u2f.bin.coffee";
+    let client_data = base64::decode_config("eyJjaGFsbGVuZ2UiOiJ4MmloTFphSWNHaEEtQnlZMm1nTGM4YW9mRU0iLCJvcmlnaW4iOiJodHRwczovL3UyZi5iaW4uY29mZmVlIiwidHlwIjoibmF2aWdhdG9yLmlkLmZpbmlzaEVucm9sbG1lbnQifQ", base64::URL_SAFE_NO_PAD).unwrap();
+    let registration_data = base64::decode_config(KRYPTON_REGISTRATION_DATA, base64::URL_SAFE_NO_PAD).unwrap();
+
+    // Reserved byte, 65 byte public key, 1 byte key handle length and a 80 byte key handle.
+    let cert_start = 1 + 65 + 1 + 80;
+    let cert_end = registration_data.len() - 70;
+
+    let parse = |len: usize| {
+        match parse_registration(app_id.to_string(), client_data.clone(), registration_data[..len].to_vec()) {
+            Ok(_) => panic!("truncated registration data accepted"),
+            Err(e) => e,
+        }
+    };
+
+    assert!(parse_registration(app_id.to_string(), client_data.clone(), registration_data.clone()).is_ok());
+
+    match parse(0) { U2fError::InvalidRegistrationData => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(40) { U2fError::TruncatedPublicKey => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(66) { U2fError::TruncatedKeyHandle => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(100) { U2fError::TruncatedKeyHandle => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(cert_start + 1) { U2fError::InvalidCertificateLength => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(cert_start + 3) { U2fError::InvalidCertificateLength => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(cert_end - 1) { U2fError::InvalidCertificateLength => (), e => panic!("unexpected error: {:?}", e) }
+    match parse(cert_end) { U2fError::MissingSignature => (), e => panic!("unexpected error: {:?}", e) }
+
+    let mut bad_reserved = registration_data.clone();
+    bad_reserved[0] = 0x04;
+    match parse_registration(app_id.to_string(), client_data.clone(), bad_reserved) {
+        Err(U2fError::InvalidReservedByte) => (),
+        r => panic!("unexpected result: {:?}", r.err()),
+    }
+}
+
+#[test]
+fn test_asn_length() {
+    use bytes::Bytes;
+    use crate::util::asn_length;
+
+    assert_eq!(asn_length(Bytes::from(&[0x30, 0x03, 0x02, 0x01, 0x00][..])).unwrap(), 5);
+    assert_eq!(asn_length(Bytes::from(&[0x30, 0x82, 0x01, 0x27][..])).unwrap(), 0x127 + 4);
+    assert!(asn_length(Bytes::from(&[0x30][..])).is_err());
+    assert!(asn_length(Bytes::from(&[0x31, 0x03][..])).is_err());
+    assert!(asn_length(Bytes::from(&[0x30, 0x82, 0x01][..])).is_err());
+    assert!(asn_length(Bytes::from(&[0x30, 0xff, 0xff, 0xff][..])).is_err());
+    assert!(asn_length(Bytes::from(&[0x30, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..])).is_err());
+}
+
+#[test]
+fn test_fuzz_regressions() {
+    use crate::fuzzing;
+
+    let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+
+    for target in std::fs::read_dir(root).unwrap() {
+        let target = target.unwrap();
+        let run: fn(&[u8]) = match target.file_name().to_str().unwrap() {
+            "parse_registration" => fuzzing::registration,
+            "parse_sign_response" => fuzzing::sign_response,
+            "asn_length" => fuzzing::asn_length,
+            "public_key" => fuzzing::public_key,
+            "messages" => fuzzing::messages,
+            name => panic!("unknown fuzz target: {}", name),
+        };
+
+        for input in std::fs::read_dir(target.path()).unwrap() {
+            let input = std::fs::read(input.unwrap().path()).unwrap();
+            run(&input);
+        }
+    }
+}
+
+// Issues a P-256 certificate for `cn`, signed by `issuer` (common name and private key) or
+// self-signed. Returns the DER encoded certificate and its private key.
+fn make_cert(cn: &str, issuer: Option<(&str, &[u8])>, ca: bool) -> (Vec<u8>, Vec<u8>) {
+    let crypto = default_provider();
+    let key = crypto.generate_p256_key().unwrap();
+    let public_key = crypto.p256_public_key(&key).unwrap();
+
+    let mut extensions = vec![];
+    if ca {
+        // basicConstraints cA, keyUsage keyCertSign
+        extensions.push(soft_token::extension(&[0x55, 0x1d, 0x13], true, &der::sequence(&[&der::tlv(der::BOOLEAN, &[0xff])])));
+        extensions.push(soft_token::extension(&[0x55, 0x1d, 0x0f], true, &[0x03, 0x02, 0x02, 0x04]));
+    }
+
+    let (issuer, issuer_key) = issuer.unwrap_or((cn, &key));
+    let cert = soft_token::certificate(&*crypto, cn, &public_key, issuer, issuer_key, &extensions).unwrap();
+
+    (cert, key)
+}
+
+fn to_pem(der: &[u8]) -> Vec<u8> {
+    let base64 = base64::encode(der);
+    let lines: Vec<&str> = base64.as_bytes().chunks(64).map(|line| std::str::from_utf8(line).unwrap()).collect();
+
+    format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", lines.join("\n")).into_bytes()
+}
+
+#[test]
+fn test_attestation_trust_store() {
+    let (root, root_key) = make_cert("Test Root", None, true);
+    let (intermediate, intermediate_key) = make_cert("Test Intermediate", Some(("Test Root", &root_key)), true);
+    let (leaf, _) = make_cert("Test Device", Some(("Test Intermediate", &intermediate_key)), false);
+    let (other_root, _) = make_cert("Other Root", None, true);
+    let (self_signed, _) = make_cert("Test Device", None, false);
+
+    let mut store = AttestationTrustStore::new();
+    store.add_root_pem(&to_pem(&root)).unwrap();
+    match store.verify(&leaf) {
+        Err(U2fError::NotTrustedAnchor) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    store.add_intermediate_der(&intermediate).unwrap();
+    store.verify(&leaf).unwrap();
+    for crypto in crypto_providers() {
+        store.verify_with(&*crypto, &leaf).unwrap();
+    }
+
+    let mut other = AttestationTrustStore::new();
+    other.add_root_der(&other_root).unwrap();
+    other.add_intermediate_pem(&to_pem(&intermediate)).unwrap();
+    assert!(other.verify(&leaf).is_err());
+
+    // Only CAs issue certificates.
+    let (not_ca, not_ca_key) = make_cert("Test Issuer", Some(("Test Root", &root_key)), false);
+    let (below_not_ca, _) = make_cert("Test Device", Some(("Test Issuer", &not_ca_key)), false);
+    let mut not_ca_store = AttestationTrustStore::new();
+    not_ca_store.add_root_der(&root).unwrap();
+    not_ca_store.add_intermediate_der(&not_ca).unwrap();
+    not_ca_store.verify(&not_ca).unwrap();
+    assert!(not_ca_store.verify(&below_not_ca).is_err());
+
+    let listed = AttestationPolicy::SelfAttestationFor(vec!["Test Device".to_string()]);
+    let unlisted = AttestationPolicy::SelfAttestationFor(vec!["Other Device".to_string()]);
+    store.check(&AttestationPolicy::Any, &self_signed).unwrap();
+    store.check(&listed, &self_signed).unwrap();
+    store.check(&listed, &leaf).unwrap();
+    assert!(store.check(&AttestationPolicy::TrustedChain, &self_signed).is_err());
+    assert!(store.check(&unlisted, &self_signed).is_err());
+}
+
+#[test]
+fn test_register_attestation_policy() {
+    let app_id = "https://u2f.bin.coffee";
+
+    let reg = r#"{"version": "U2F_V2","challenge": "x2ihLZaIcGhA-ByY2mgLc8aofEM"}"#;
+    let resp = r#"
+    {
+      "clientData": "eyJjaGFsbGVuZ2UiOiJ4MmloTFphSWNHaEEtQnlZMm1nTGM4YW9mRU0iLCJvcmlnaW4iOiJodHRwczovL3UyZi5iaW4uY29mZmVlIiwidHlwIjoibmF2aWdhdG9yLmlkLmZpbmlzaEVucm9sbG1lbnQifQ",
+      "registrationData": "BQS53KgoebC9HkJSbZM2r7C9oOnEysjR06iSnglpQIs6KeaCFwKQx6XbmrM2-p9BbdNOPvhF0GtUwNp7g7HznOIUUCzlyN8X4i7yD9ODA_0tmZjg1CmSI9If20U86SgMBqrcrK0radduqslZczEtivFMKXaaeqMT2rs7jfMb124XtnCwp4u5lCWVLYWMhmKyPlraMIIBJzCBzqADAgECAgF7MAoGCCqGSM49BAMCMBYxFDASBgNVBAMMC0tyeXB0b24gS2V5MB4XDTIwMDEyNTIyNTMyOVoXDTMwMDEyNTEwNTMyOVowFjEUMBIGA1UEAwwLS3J5cHRvbiBLZXkwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAS53KgoebC9HkJSbZM2r7C9oOnEysjR06iSnglpQIs6KeaCFwKQx6XbmrM2-p9BbdNOPvhF0GtUwNp7g7HznOIUow0wCzAJBgNVHRMEAjAAMAoGCCqGSM49BAMCA0gAMEUCIQDeU4DwRJV_CAcormHMaYBYeTkFNQuUQsK77PF7jzy14QIgfXP5iop-DQqQjVkJUD11WeRvKCqZWRhyleQcRmsj584wRAIgf4vqsRgB6azPwVGGG6EDx4ioThOyLEfo8GPHWe7Pva8CIBE9P0-RlFgVOPQZFGlFWtzqzIy-3l4BkmIYgpSILlYt",
+      "version": "U2F_V2"
+    }
+    "#;
+
+    let register = |crypto: Arc<dyn CryptoProvider>, store: AttestationTrustStore, policy: AttestationPolicy| {
+        let reg: RegisterRequest = serde_json::from_str(reg).unwrap();
+        let resp: RegisterResponse = serde_json::from_str(resp).unwrap();
+        let challenge = Challenge {
+            app_id: app_id.to_string(),
+            challenge: reg.challenge,
+            timestamp: format!("{:?}", chrono::Utc::now()),
+        };
+
+        U2f::new(app_id.to_string())
+            .with_crypto_provider(crypto)
+            .with_attestation(store, policy)
+            .register_response(challenge, resp)
+    };
+
+    let krypton = verify_register(app_id, reg, resp).attestation_cert.unwrap();
+    for crypto in crypto_providers() {
+        match register(crypto.clone(), AttestationTrustStore::new(), AttestationPolicy::TrustedChain) {
+            Err(U2fError::NotTrustedAnchor) => (),
+            r => panic!("unexpected result: {:?}", r.err()),
+        }
+
+        let mut store = AttestationTrustStore::new();
+        store.add_root_der(&krypton).unwrap();
+        assert!(register(crypto.clone(), store, AttestationPolicy::TrustedChain).is_ok());
+
+        let policy = AttestationPolicy::SelfAttestationFor(vec!["Krypton Key".to_string()]);
+        assert!(register(crypto, AttestationTrustStore::new(), policy).is_ok());
+    }
+}
+
+// Signs `payload` as an ES256 JWT carrying `chain` in its x5c header.
+fn make_jwt(payload: &serde_json::Value, chain: &[&[u8]], key: &[u8]) -> String {
+    let x5c: Vec<String> = chain.iter().map(base64::encode).collect();
+    let header = serde_json::json!({ "alg": "ES256", "typ": "JWT", "x5c": x5c });
+
+    let signed_data = format!("{}.{}",
+        base64::encode_config(header.to_string().as_bytes(), base64::URL_SAFE_NO_PAD),
+        base64::encode_config(payload.to_string().as_bytes(), base64::URL_SAFE_NO_PAD));
+
+    // JWS signatures are r || s, each padded to 32 bytes, rather than DER.
+    let signature = default_provider().sign_p256(key, signed_data.as_bytes()).unwrap();
+    let (fields, _) = der::expect(&signature, der::SEQUENCE).unwrap();
+    let (r, fields) = der::expect(fields, der::INTEGER).unwrap();
+    let (s, _) = der::expect(fields, der::INTEGER).unwrap();
+    let mut raw = vec![];
+    for integer in &[r, s] {
+        let integer: Vec<u8> = integer.iter().copied().skip_while(|b| *b == 0).collect();
+        raw.extend(vec![0u8; 32 - integer.len()]);
+        raw.extend(integer);
+    }
+
+    format!("{}.{}", signed_data, base64::encode_config(&raw, base64::URL_SAFE_NO_PAD))
+}
+
+#[test]
+fn test_metadata_blob() {
+    let app_id = "https://u2f.bin.coffee";
+    let reg = r#"{"version": "U2F_V2","challenge": "mjvdwudayivfuRrtTtxvej9BuGg"}"#;
+    let resp = r#"
+    {
+      "clientData": "eyJjaGFsbGVuZ2UiOiJtanZkd3VkYXlpdmZ1UnJ0VHR4dmVqOUJ1R2ciLCJvcmlnaW4iOiJodHRwczovL3UyZi5iaW4uY29mZmVlIiwidHlwIjoibmF2aWdhdG9yLmlkLmZpbmlzaEVucm9sbG1lbnQifQ",
+      "registrationData": "BQS--mwrFPzFsoiZYDqF_lr7MRayrQ8d5qvI3pG4P1Nbwzsc1p7Ew7AZ3fPGMiRTyp6xlOVMQYTUfe9C0gLYjQhaUCzlyN8X4i7yD9ODA_0tmZgfc4YP3fnZN_Wc83KUq-I7jNXEQfARXX1DF9rDrHiXpfz3WhAGFigln8hJhT_Ts28cHsE5lGtukPlVv8Y623krMIIBJzCBzqADAgECAgF7MAoGCCqGSM49BAMCMBYxFDASBgNVBAMMC0tyeXB0b24gS2V5MB4XDTIwMDEyNTIzMDY1N1oXDTMwMDEyNTExMDY1N1owFjEUMBIGA1UEAwwLS3J5cHRvbiBLZXkwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAS--mwrFPzFsoiZYDqF_lr7MRayrQ8d5qvI3pG4P1Nbwzsc1p7Ew7AZ3fPGMiRTyp6xlOVMQYTUfe9C0gLYjQhaow0wCzAJBgNVHRMEAjAAMAoGCCqGSM49BAMCA0gAMEUCIQDCC5erfPV34xhRxjcwCwRFrqnN8vpTsFKid3DB-pD2rgIgIjF40n3AvaCC2WGioKUbzXFXGQ0oFaALOEnnxTePmTswRQIgVY1DCRidvgaY6hr6vm1ImkhQ9E2TkUOeqf61QIPPVzACIQCxh8-_9pRpnklk6h4eCjxFFD6vKSwU9EoeNKoz1EBGuA",
+      "version": "U2F_V2"
+    }
+    "#;
+    let registration = verify_register(app_id, reg, resp);
+    let attestation_cert = registration.attestation_cert.clone().unwrap();
+
+    // Krypton keys attest with the registered key itself, so the key identifier
+    // is the SHA-1 of the user public key.
+    let key_identifier = hex::encode(default_provider().sha1(&registration.pub_key));
+
+    let (root, root_key) = make_cert("MDS Root", None, true);
+    let (signer, signer_key) = make_cert("MDS Signer", Some(("MDS Root", &root_key)), false);
+    let (other_root, _) = make_cert("Other Root", None, true);
+
+    let payload = serde_json::json!({
+        "legalHeader": "test",
+        "no": 7,
+        "nextUpdate": "2030-01-01",
+        "entries": [{
+            "attestationCertificateKeyIdentifiers": [key_identifier.to_uppercase()],
+            "metadataStatement": {
+                "description": "Krypton Key",
+                "protocolFamily": "u2f",
+                "attestationRootCertificates": [base64::encode(&attestation_cert)]
+            },
+            "statusReports": [
+                { "status": "FIDO_CERTIFIED_L1", "effectiveDate": "2020-01-01" },
+                { "status": "REVOKED", "effectiveDate": "2021-06-01" },
+                { "status": "SOME_FUTURE_STATUS", "effectiveDate": "2019-01-01" }
+            ]
+        }]
+    });
+    let blob = make_jwt(&payload, &[&signer], &signer_key);
+
+    let mds = MetadataService::from_blob(&blob, &root).unwrap();
+    assert_eq!(mds.payload().no, 7);
+    for crypto in crypto_providers() {
+        MetadataService::from_blob_with(&*crypto, &blob, &root).unwrap();
+    }
+
+    let entry = mds.lookup(&registration).unwrap();
+    let statement = entry.metadata_statement.as_ref().unwrap();
+    assert_eq!(statement.description, "Krypton Key");
+    assert_eq!(entry.status(), Some(&AuthenticatorStatus::Revoked));
+    assert_eq!(entry.status_reports[2].status, AuthenticatorStatus::Unknown);
+    assert!(entry.is_compromised());
+    statement.trust_store().unwrap().verify(&attestation_cert).unwrap();
+
+    match MetadataService::from_blob(&blob, &other_root) {
+        Err(U2fError::NotTrustedAnchor) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let parts: Vec<&str> = blob.split('.').collect();
+    let tampered = format!("{}.{}.{}", parts[0], base64::encode_config(b"{\"no\":8,\"nextUpdate\":\"\",\"entries\":[]}", base64::URL_SAFE_NO_PAD), parts[2]);
+    match MetadataService::from_blob(&tampered, &root) {
+        Err(U2fError::BadSignature) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    assert!(MetadataService::from_blob("not a jwt", &root).is_err());
+}
+
+#[test]
+fn test_device_info_extensions() {
+    let (cert, key) = make_cert("Test Device", None, false);
+    let crypto = default_provider();
+    let public_key = crypto.p256_public_key(&key).unwrap();
+
+    let mut aaguid = vec![0x04, 0x10];
+    aaguid.extend((0..16).collect::<Vec<u8>>());
+    let extensions = [
+        // 1.3.6.1.4.1.45724.2.1.1, USB and NFC
+        soft_token::extension(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xe5, 0x1c, 0x02, 0x01, 0x01], false, &[0x03, 0x02, 0x04, 0x30]),
+        // 1.3.6.1.4.1.45724.1.1.4
+        soft_token::extension(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xe5, 0x1c, 0x01, 0x01, 0x04], false, &aaguid),
+        // 1.3.6.1.4.1.41482.1.1
+        soft_token::extension(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xc4, 0x0a, 0x01, 0x01], false, &[0x05, 0x00]),
+    ];
+    let with_extensions = soft_token::certificate(&*crypto, "Test Device", &public_key, "Test Device", &key, &extensions).unwrap();
+
+    let device = DeviceInfo::from_certificate(&with_extensions).unwrap();
+    assert_eq!(device.transports, vec![Transport::Usb, Transport::Nfc]);
+    assert_eq!(device.aaguid.as_ref().unwrap(), "00010203-0405-0607-0809-0a0b0c0d0e0f");
+    assert_eq!(device.to_string(), "Security Key by Yubico (USB, NFC)");
+    assert_eq!(device.signature_algorithm.as_ref().unwrap(), "ecdsa-with-SHA256");
+
+    let device = DeviceInfo::from_certificate(&cert).unwrap();
+    assert_eq!(device.to_string(), "Test Device");
+    assert!(device.aaguid.is_none());
+}
+
+// Signs an assertion for `challenge` the way a token would, with the given user presence byte and counter.
+fn make_sign_response(key: &[u8], key_handle: &[u8], app_id: &str, challenge: &str, flags: u8, counter: u32) -> SignResponse {
+    let client_data = format!(r#"{{"typ":"navigator.id.getAssertion","challenge":"{}","origin":"{}"}}"#, challenge, app_id);
+
+    let crypto = default_provider();
+    let mut msg = crypto.sha256(app_id.as_bytes()).to_vec();
+    msg.push(flags);
+    msg.extend(&counter.to_be_bytes());
+    msg.extend(&crypto.sha256(client_data.as_bytes()));
+
+    let mut signature_data = vec![flags];
+    signature_data.extend(&counter.to_be_bytes());
+    signature_data.extend(crypto.sign_p256(key, &msg).unwrap());
+
+    SignResponse {
+        key_handle: base64::encode_config(key_handle, base64::URL_SAFE_NO_PAD),
+        signature_data: base64::encode_config(&signature_data, base64::URL_SAFE_NO_PAD),
+        client_data: base64::encode_config(client_data.as_bytes(), base64::URL_SAFE_NO_PAD),
+    }
+}
+
+// A registration for a freshly generated P-256 key, as a token would have created it.
+fn make_registration() -> (Registration, Vec<u8>) {
+    let crypto = default_provider();
+    let key = crypto.generate_p256_key().unwrap();
+    let pub_key = crypto.p256_public_key(&key).unwrap();
+
+    let registration = Registration {
+        key_handle: (0..64).collect(),
+        pub_key,
+        attestation_cert: None,
+        metadata: Default::default(),
+    };
+
+    (registration, key)
+}
+
+#[test]
+fn test_sign_user_presence() {
+    let app_id = "https://u2f.bin.coffee";
+    let u2f = U2f::new(app_id.to_string());
+    let (reg, key) = make_registration();
+    let challenge = u2f.generate_challenge().unwrap();
+
+    let present = make_sign_response(&key, &reg.key_handle, app_id, &challenge.challenge, 0x01, 5);
+    assert_eq!(u2f.sign_response(challenge.clone(), reg.clone(), present, 0).unwrap().counter, 5);
+
+    let silent = make_sign_response(&key, &reg.key_handle, app_id, &challenge.challenge, 0x00, 6);
+    match u2f.sign_response(challenge.clone(), reg.clone(), silent.clone(), 0) {
+        Err(U2fError::InvalidUserPresenceByte) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let u2f = u2f.with_silent_authentication(true);
+    let auth = u2f.sign_response(challenge, reg, silent, 0).unwrap();
+    assert_eq!(auth.counter, 6);
+    assert!(!auth.user_presence);
+}
+
+#[test]
+fn test_counter_policy() {
+    let app_id = "https://u2f.bin.coffee";
+    let (reg, key) = make_registration();
+
+    let sign = |policy: CounterPolicy, stored: u32, received: u32| {
+        let u2f = U2f::new(app_id.to_string()).with_counter_policy(policy);
+        let challenge = u2f.generate_challenge().unwrap();
+        let resp = make_sign_response(&key, &reg.key_handle, app_id, &challenge.challenge, 0x01, received);
+        u2f.sign_response(challenge, reg.clone(), resp, stored).map(|auth| auth.possible_clone)
+    };
+
+    assert!(!sign(CounterPolicy::StrictIncrease, 4, 5).unwrap());
+    assert!(sign(CounterPolicy::StrictIncrease, 5, 5).is_err());
+    assert!(sign(CounterPolicy::StrictIncrease, 0, 0).is_err());
+
+    assert!(sign(CounterPolicy::AllowEqual, 5, 5).unwrap());
+    assert!(!sign(CounterPolicy::AllowEqual, 5, 6).unwrap());
+    match sign(CounterPolicy::AllowEqual, 5, 4) {
+        Err(U2fError::CounterTooLow) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    assert!(!sign(CounterPolicy::IgnoreZeroCounter, 0, 0).unwrap());
+    assert!(sign(CounterPolicy::IgnoreZeroCounter, 3, 0).is_err());
+
+    assert!(!sign(CounterPolicy::WarnOnly, 5, 6).unwrap());
+    assert!(sign(CounterPolicy::WarnOnly, 5, 5).unwrap());
+    assert!(sign(CounterPolicy::WarnOnly, 5, 1).unwrap());
+}
+
+#[test]
+fn test_sign_response_any() {
+    let app_id = "https://u2f.bin.coffee";
+    let u2f = U2f::new(app_id.to_string());
+
+    let (first, _) = make_registration();
+    let (mut second, key) = make_registration();
+    second.key_handle = vec![7; 64];
+    let registrations = vec![first.clone(), second.clone()];
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let resp = make_sign_response(&key, &second.key_handle, app_id, &challenge.challenge, 0x01, 12);
+
+    let matched = u2f.sign_response_any(challenge.clone(), &registrations, resp.clone(), &[3, 10]).unwrap();
+    assert_eq!(matched.index, 1);
+    assert_eq!(matched.authorization.counter, 12);
+
+    match u2f.sign_response_any(challenge.clone(), &registrations, resp.clone(), &[3, 12]) {
+        Err(U2fError::CounterTooLow) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    match u2f.sign_response_any(challenge.clone(), &registrations[..1], resp.clone(), &[3]) {
+        Err(U2fError::WrongKeyHandler) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    match u2f.sign_response_any(challenge, &registrations, resp, &[3]) {
+        Err(U2fError::MissingCounter) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_challenge_round_trip() {
+    let app_id = "https://u2f.bin.coffee";
+    let u2f = U2f::new(app_id.to_string());
+    let (reg, key) = make_registration();
+
+    let challenge = u2f.generate_challenge().unwrap();
+    assert_eq!(challenge.bytes().unwrap().len(), 32);
+
+    // Registration and authentication put the same string on the wire.
+    let register_request = u2f.request(challenge.clone(), vec![reg.clone()]).unwrap();
+    let sign_request = u2f.sign_request(challenge.clone(), vec![reg.clone()]);
+    assert_eq!(register_request.register_requests[0].challenge, challenge.challenge);
+    assert_eq!(sign_request.challenge, challenge.challenge);
+
+    // The token signs whatever the browser received in the sign request.
+    let resp = make_sign_response(&key, &reg.key_handle, app_id, &sign_request.challenge, 0x01, 1);
+    assert_eq!(u2f.sign_response(challenge.clone(), reg, resp, 0).unwrap().counter, 1);
+
+    // Rebuilding a challenge from its bytes and timestamp gives back the issued one.
+    let rebuild = |c: &Challenge| Challenge::from_bytes(c.app_id.clone(), &c.bytes().unwrap(), c.timestamp.parse().unwrap());
+    assert_eq!(rebuild(&challenge), challenge);
+
+    // A token answering the rebuilt challenge satisfies the issued one, in both ceremonies.
+    let mut token = SoftToken::new().unwrap();
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.register(&u2f.request(rebuild(&challenge), vec![]).unwrap()).unwrap();
+    let reg = u2f.register_response(challenge, response).unwrap();
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.sign(&u2f.sign_request(rebuild(&challenge), vec![reg.clone()])).unwrap();
+    assert_eq!(u2f.sign_response(challenge, reg, response, 0).unwrap().counter, 1);
+}
+
+#[test]
+fn test_registration_storage() {
+    let (mut reg, _) = make_registration();
+    reg.attestation_cert = Some(vec![0x30, 0x00]);
+    reg.metadata.nickname = Some("Backup key".to_string());
+    reg.metadata.created_at = Some("2020-01-25T23:06:57Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap());
+    reg.record_use(42, "2020-02-01T08:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap());
+
+    let stored = serde_json::to_value(&reg).unwrap();
+    assert_eq!(stored["version"], 1);
+    assert_eq!(stored["keyHandle"], base64::encode_config(&reg.key_handle, base64::URL_SAFE_NO_PAD));
+    assert_eq!(stored["attestationCert"], "MAA");
+    assert_eq!(stored["counter"], 42);
+    assert_eq!(stored["lastUsed"], "2020-02-01T08:00:00Z");
+
+    let loaded: Registration = serde_json::from_value(stored).unwrap();
+    assert_eq!(loaded.key_handle, reg.key_handle);
+    assert_eq!(loaded.pub_key, reg.pub_key);
+    assert_eq!(loaded.attestation_cert, reg.attestation_cert);
+    assert_eq!(loaded.metadata, reg.metadata);
+
+    // Registrations serialized by earlier releases, with byte arrays and no version.
+    let legacy = serde_json::json!({
+        "keyHandle": reg.key_handle,
+        "pubKey": reg.pub_key,
+        "attestationCert": null,
+    });
+    let migrated: Registration = serde_json::from_value(legacy).unwrap();
+    assert_eq!(migrated.key_handle, reg.key_handle);
+    assert_eq!(migrated.pub_key, reg.pub_key);
+    assert!(migrated.attestation_cert.is_none());
+    assert_eq!(migrated.metadata, RegistrationMetadata::default());
+    assert_eq!(serde_json::to_value(&migrated).unwrap()["version"], 1);
+
+    let future = serde_json::json!({ "version": 2, "keyHandle": "AA", "pubKey": "AA" });
+    assert!(serde_json::from_value::<Registration>(future).is_err());
+
+    // A malformed current registration reports why it is not valid V1, not the legacy mismatch.
+    let malformed = serde_json::json!({ "version": 1, "keyHandle": "AA" });
+    let error = serde_json::from_value::<Registration>(malformed).err().unwrap().to_string();
+    assert!(error.contains("pubKey"), "{}", error);
+}
+
+#[test]
+fn test_js_api_messages() {
+    let app_id = "https://localhost";
+    let u2f = U2f::new(app_id.into()).with_timeout_seconds(30);
+    let (reg, _key) = make_registration();
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let register = serde_json::to_value(u2f.request(challenge.clone(), vec![reg.clone()]).unwrap()).unwrap();
+    assert_eq!(register["type"], "u2f_register_request");
+    assert_eq!(register["timeoutSeconds"], 30);
+    assert!(register.get("requestId").is_none());
+
+    let sign = serde_json::to_value(u2f.sign_request(challenge, vec![reg])).unwrap();
+    assert_eq!(sign["type"], "u2f_sign_request");
+    let parsed: U2fSignRequest = serde_json::from_value(sign).unwrap();
+    assert_eq!(parsed.registered_keys.len(), 1);
+    assert_eq!(parsed.timeout_seconds, Some(30));
+
+    let ok: U2fSignResponse = serde_json::from_value(serde_json::json!({
+        "type": "u2f_sign_response",
+        "responseData": { "keyHandle": "AA", "signatureData": "AA", "clientData": "AA" },
+        "requestId": 7,
+    }))
+    .unwrap();
+    assert_eq!(ok.request_id, Some(7));
+    assert!(matches!(ok.response_data, SignResponseData::Response(_)));
+
+    let err: U2fSignResponse = serde_json::from_value(serde_json::json!({
+        "type": "u2f_sign_response",
+        "responseData": { "errorCode": 4, "errorMessage": "not registered" },
+    }))
+    .unwrap();
+    match err.response_data {
+        SignResponseData::Error(e) => {
+            assert_eq!(e, ErrorResponse::DeviceIneligible(Some("not registered".into())));
+            assert_eq!(e.to_string(), "DEVICE_INELIGIBLE (4): not registered");
+        }
+        _ => panic!("expected an error response"),
+    }
+
+    assert_eq!(serde_json::to_value(ErrorResponse::Timeout(None)).unwrap(), serde_json::json!({ "errorCode": 5 }));
+    assert!(serde_json::from_value::<ErrorResponse>(serde_json::json!({ "errorCode": 9 })).is_err());
+}
+
+#[test]
+fn test_legacy_js_api() {
+    let app_id = "https://u2f.bin.coffee";
+    let u2f = U2f::new(app_id.into()).with_api_version(ApiVersion::V1_0);
+    let (reg, _key) = make_registration();
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let register = u2f.register_message(challenge.clone(), vec![reg.clone()]).unwrap();
+    assert!(matches!(register, RegisterRequestMessage::V1_0(_)));
+    let register = serde_json::to_value(register).unwrap();
+    assert!(register.get("type").is_none());
+    assert_eq!(register["registerRequests"][0]["appId"], app_id);
+    assert_eq!(register["registerRequests"][0]["challenge"], challenge.challenge.as_str());
+    assert_eq!(register["signRequests"][0]["keyHandle"], base64::encode_config(&reg.key_handle, base64::URL_SAFE_NO_PAD));
+
+    let sign = serde_json::to_value(u2f.sign_message(challenge.clone(), vec![reg.clone()])).unwrap();
+    assert_eq!(sign["signRequests"][0]["version"], "U2F_V2");
+    assert_eq!(sign["signRequests"][0]["challenge"], challenge.challenge.as_str());
+    match serde_json::from_value::<SignRequestMessage>(sign).unwrap() {
+        SignRequestMessage::V1_0(request) => assert_eq!(request.sign_requests.len(), 1),
+        _ => panic!("expected a v1.0 sign request"),
+    }
+
+    let current = U2f::new(app_id.into()).sign_message(challenge, vec![reg]);
+    assert!(matches!(current, SignRequestMessage::V1_1(_)));
+
+    // v1.0 register responses carry no version.
+    let req = r#"{ "version": "U2F_V2", "challenge": "6P5JxkcBo1n7MkYedNHMfasfv2U"}"#;
+    let resp = r#"
+    {"registrationData": "BQT0I6ocSkELiqqRc2MGai1raa3F49Q1d03UgWzu2eCADhPgSvXJsKzUIYERji0vxDlAElc4sZdm2ewnYXnDFOFrQAfHLIuUlJU3XsbiR9yO2kungl9EQB191MQm6sUx1-yE24i_KckdQzys5eel9hkLpFCptTi81FeaidzFd1DENqkwggEcMIHDoAMCAQICCwCqfKUQ4WrQsbjQMAoGCCqGSM49BAMCMBUxEzARBgNVBAMTClUyRiBJc3N1ZXIwGhcLMDAwMTAxMDAwMFoXCzAwMDEwMTAwMDBaMBUxEzARBgNVBAMTClUyRiBEZXZpY2UwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQLHNI5rXnrTB7a8UNEHI-1V1-WytEjyFyzE9XGbSVGIShjp3F8efxCDss3RgQVkRwKnEhlZt3npGa12j1zF3ttMAoGCCqGSM49BAMCA0gAMEUCIQDBo6aOLxanIUYnBX9iu3KMngPnobpi0EZSTkVtLC8_cwIgC1945RGqGBKfbyNtkhMifZK05n7fU-gW37Bdnci5D94wRQIgDl3K_Fefznq8etzJVSO75zaULRnyXWJhkGspAaXpqVsCIQC2M6zC5tpztFaBLpxV2JElJTyzN0pJ8uza-bkfAxBuXQ",
+    "clientData": "eyJjaGFsbGVuZ2UiOiI2UDVKeGtjQm8xbjdNa1llZE5ITWZhc2Z2MlUiLCJvcmlnaW4iOiJodHRwczovL3UyZi5iaW4uY29mZmVlIiwidHlwIjoibmF2aWdhdG9yLmlkLmZpbmlzaEVucm9sbG1lbnQifQ"
+    }"#;
+
+    let reg = verify_register(app_id, req, resp);
+    assert_eq!(reg.subject().as_ref().unwrap(), "U2F Device");
+}
+
+#[test]
+fn test_app_id_facets() {
+    assert!(AppId::parse("https://u2f.bin.coffee").is_ok());
+    assert!(AppId::parse("https://localhost:8080/app-id.json").is_ok());
+    for invalid in &["http://example.com", "https://com", "https://co.uk", "https://github.io", "https://foo.ck", "https://corp", "https://internal", "example.com", "https://user@example.com"] {
+        match AppId::parse(invalid) {
+            Err(U2fError::InvalidAppId) => (),
+            r => panic!("{} accepted: {:?}", invalid, r),
+        }
+    }
+
+    assert!(is_public_suffix("co.jp"));
+    assert!(is_public_suffix("anything.kobe.jp"));
+    assert!(!is_public_suffix("city.kobe.jp"));
+    // 公司.cn, 中国 and ελ, listed in Unicode.
+    assert!(is_public_suffix("xn--55qx5d.cn"));
+    assert_eq!(effective_domain("www.example.xn--fiqs8s").unwrap(), "example.xn--fiqs8s");
+    assert!(is_public_suffix("xn--qxam"));
+    assert_eq!(effective_domain("login.example.co.uk").unwrap(), "example.co.uk");
+    assert_eq!(effective_domain("a.b.foo.ck").unwrap(), "b.foo.ck");
+    assert_eq!(effective_domain("www.ck").unwrap(), "www.ck");
+    assert_eq!(effective_domain("api.example.internal").unwrap(), "example.internal");
+    assert!(effective_domain("co.uk").is_none());
+    // Unlisted single labels are public suffixes under the implicit "*" rule.
+    assert!(is_public_suffix("corp"));
+    assert!(effective_domain("corp").is_none());
+    assert!(!is_public_suffix("example.corp"));
+    assert!(!is_public_suffix("127.0.0.1"));
+
+    let app_id = AppId::parse("https://example.com/u2f/app-id.json").unwrap();
+    assert_eq!(app_id.facet_id(), "https://example.com");
+    assert!(app_id.is_authorized("https://example.com"));
+    assert!(app_id.is_authorized("https://EXAMPLE.com:443"));
+    assert!(!app_id.is_authorized("http://example.com"));
+    assert!(!app_id.is_authorized("https://login.example.com"));
+    assert!(!app_id.is_authorized("https://example.com.evil.net"));
+
+    assert!(app_id.shares_effective_domain("https://login.example.com"));
+    assert!(!app_id.shares_effective_domain("https://example.net"));
+    assert!(!app_id.shares_effective_domain("http://login.example.com"));
+    let hosted = AppId::parse("https://alice.github.io").unwrap();
+    assert!(!hosted.shares_effective_domain("https://mallory.github.io"));
+
+    // Client data origins are checked against the app ID.
+    let client_data = r#"{"challenge":"6P5JxkcBo1n7MkYedNHMfasfv2U","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+    match register_error("https://coffee", "6P5JxkcBo1n7MkYedNHMfasfv2U", client_data) {
+        U2fError::InvalidAppId => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+    match register_error("https://bin.coffee", "6P5JxkcBo1n7MkYedNHMfasfv2U", client_data) {
+        U2fError::OriginMismatch => (),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    assert!(U2f::try_new("https://example.com".into()).is_ok());
+    for invalid in &["http://example.com", "https://co.uk", "example.com"] {
+        assert!(U2f::try_new(invalid.to_string()).is_err(), "{} accepted", invalid);
+    }
+
+    // A challenge rewritten by the client to name its own origin is refused.
+    let u2f = U2f::new("https://u2f.bin.coffee".into());
+    let mut challenge = u2f.generate_challenge().unwrap();
+    challenge.app_id = "https://evil.example".into();
+    let client_data = format!(r#"{{"challenge":"{}","origin":"https://evil.example","typ":"navigator.id.finishEnrollment"}}"#, challenge.challenge);
+    let resp = RegisterResponse {
+        registration_data: String::new(),
+        version: "U2F_V2".to_string(),
+        client_data: base64::encode_config(client_data.as_bytes(), base64::URL_SAFE_NO_PAD),
+    };
+    match u2f.register_response(challenge, resp) {
+        Err(U2fError::InvalidAppId) => (),
+        r => panic!("unexpected result: {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_trusted_facets() {
+    let app_id = "https://example.com/u2f/app-id.json";
+
+    let list = TrustedFacetsBuilder::new(app_id.into())
+        .with_web_facet("https://login.example.com:443")
+        .with_web_facet("https://login.example.com")
+        .with_android_facet("FD18FA800DD00C0D9D7724728B6C0E1D2A6A2CFAFDD9AC9CE9D7C2F6E3E3B9A5")
+        .with_ios_facet("com.example.app")
+        .build()
+        .unwrap();
+    let document = serde_json::to_value(&list).unwrap();
+    assert_eq!(document, serde_json::json!({
+        "trustedFacets": [{
+            "version": { "major": 1, "minor": 0 },
+            "ids": [
+                "https://login.example.com",
+                "android:apk-key-hash:FD18FA800DD00C0D9D7724728B6C0E1D2A6A2CFAFDD9AC9CE9D7C2F6E3E3B9A5",
+                "ios:bundle-id:com.example.app",
+            ],
+        }],
+    }));
+
+    for facet in &["http://login.example.com", "https://login.example.net", "ftp:whatever"] {
+        match TrustedFacetsBuilder::new(app_id.into()).with_web_facet(facet).build() {
+            Err(U2fError::InvalidFacet) => (),
+            r => panic!("{} accepted: {:?}", facet, r),
+        }
+    }
+
+    // A list served by someone else may name facets outside the effective domain, newer
+    // versions or unknown schemes; those entries must not authorize anything.
+    let list: TrustedFacetsList = serde_json::from_str(r#"{
+        "trustedFacets": [
+            { "version": { "major": 1, "minor": 0 }, "ids": ["https://old.example.com"] },
+            { "version": { "major": 1, "minor": 1 }, "ids": [
+                "https://login.example.com", "https://evil.example.net", "ios:bundle-id:com.example.app", "windows:sid:S-1-5"
+            ] },
+            { "version": { "major": 2, "minor": 0 }, "ids": ["https://future.example.com"] }
+        ]
+    }"#).unwrap();
+    assert_eq!(list.facets(), vec![
+        Facet::Web("https://login.example.com".into()),
+        Facet::Web("https://evil.example.net".into()),
+        Facet::Ios("com.example.app".into()),
+    ]);
+
+    let app_id = AppId::parse(app_id).unwrap();
+    assert!(list.is_authorized(&app_id, "https://example.com"));
+    assert!(list.is_authorized(&app_id, "https://login.example.com"));
+    assert!(list.is_authorized(&app_id, "ios:bundle-id:com.example.app"));
+    assert!(!list.is_authorized(&app_id, "https://evil.example.net"));
+    assert!(!list.is_authorized(&app_id, "https://old.example.com"));
+    assert!(!list.is_authorized(&app_id, "https://future.example.com"));
+    assert!(!list.is_authorized(&app_id, "http://login.example.com"));
+    assert!(!list.is_authorized(&app_id, "android:apk-key-hash:AAAA"));
+
+    // A facet of the list registers and signs through U2f.
+    let list = TrustedFacetsBuilder::new(app_id.to_string()).with_web_facet("https://login.example.com").build().unwrap();
+    let u2f = U2f::new(app_id.to_string()).with_trusted_facets(list);
+    let mut token = SoftToken::new().unwrap().with_facet_id("https://login.example.com");
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.register(&u2f.request(challenge.clone(), vec![]).unwrap()).unwrap();
+    match U2f::new(app_id.to_string()).register_response(challenge.clone(), response.clone()) {
+        Err(U2fError::OriginMismatch) => (),
+        r => panic!("unexpected result: {:?}", r.err()),
+    }
+    let registration = u2f.register_response(challenge, response).unwrap();
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.sign(&u2f.sign_request(challenge.clone(), vec![registration.clone()])).unwrap();
+    assert_eq!(u2f.sign_response(challenge, registration.clone(), response, 0).unwrap().counter, 1);
+
+    let mut unlisted = SoftToken::new().unwrap().with_facet_id("https://other.example.com");
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = unlisted.register(&u2f.request(challenge.clone(), vec![]).unwrap()).unwrap();
+    match u2f.register_response(challenge, response) {
+        Err(U2fError::OriginMismatch) => (),
+        r => panic!("unexpected result: {:?}", r.err()),
+    }
+}
+
+#[test]
+fn test_raw_messages() {
+    let app_id = "https://u2f.bin.coffee";
+    let client_data = r#"{"challenge":"x2ihLZaIcGhA-ByY2mgLc8aofEM","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+
+    let register = Request::register(app_id, client_data.as_bytes());
+    let short = register.encode(ApduEncoding::Short).unwrap();
+    assert_eq!(&short[..5], &[0x00, raw::INS_REGISTER, 0x03, 0x00, 0x40]);
+    assert_eq!(short.len(), 5 + 64 + 1);
+    assert_eq!(short[69], 0x00);
+    let extended = register.encode(ApduEncoding::Extended).unwrap();
+    assert_eq!(&extended[..7], &[0x00, raw::INS_REGISTER, 0x03, 0x00, 0x00, 0x00, 0x40]);
+    assert_eq!(&extended[71..], &[0x00, 0x00]);
+    assert_eq!(Request::decode(&short).unwrap(), register);
+    assert_eq!(Request::decode(&extended).unwrap(), register);
+
+    let version = Request::Version.encode(ApduEncoding::Extended).unwrap();
+    assert_eq!(version, vec![0x00, raw::INS_VERSION, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(Request::decode(&version).unwrap(), Request::Version);
+    assert_eq!(Response::decode(b"U2F_V2\x90\x00").unwrap().version().unwrap(), "U2F_V2");
+
+    let apdu = Apdu::decode(&[0x00, 0x02, 0x07, 0x00, 0x01, 0xaa]).unwrap();
+    assert_eq!((apdu.data.clone(), apdu.ne), (vec![0xaa], None));
+    for invalid in &[&[0x00, 0x01, 0x00][..], &[0x00, 0x01, 0x00, 0x00, 0x02, 0xaa], &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb, 0x00], &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00]] {
+        assert!(Apdu::decode(invalid).is_err(), "{:?} accepted", invalid);
+    }
+
+    // Token answers feed straight into the parsers.
+    let registration_data = base64::decode_config(KRYPTON_REGISTRATION_DATA, base64::URL_SAFE_NO_PAD).unwrap();
+    let response = Response::new(registration_data, raw::SW_NO_ERROR).encode();
+    let data = Response::decode(&response).unwrap().into_data().unwrap();
+    let reg = parse_registration(app_id.to_string(), client_data.as_bytes().to_vec(), data).unwrap();
+    assert_eq!(reg.subject().unwrap(), "Krypton Key");
+
+    let (reg, key) = make_registration();
+    let sign = make_sign_response(&key, &reg.key_handle, app_id, "bRLh0fxu3DvMr5ws2ylmnQ", 0x01, 3);
+    let sign_client_data = base64::decode_config(&sign.client_data, base64::URL_SAFE_NO_PAD).unwrap();
+
+    let authenticate = Request::authenticate(AuthenticateControl::EnforceUserPresence, app_id, &sign_client_data, &reg.key_handle);
+    match Request::decode(&authenticate.encode(ApduEncoding::Extended).unwrap()).unwrap() {
+        Request::Authenticate { control, key_handle, .. } => {
+            assert_eq!(control, AuthenticateControl::EnforceUserPresence);
+            assert_eq!(key_handle, reg.key_handle);
+        }
+        r => panic!("unexpected request: {:?}", r),
+    }
+
+    let signature_data = base64::decode_config(&sign.signature_data, base64::URL_SAFE_NO_PAD).unwrap();
+    let response = Response::decode(&Response::new(signature_data, raw::SW_NO_ERROR).encode()).unwrap();
+    let auth = parse_sign_response(app_id.to_string(), "bRLh0fxu3DvMr5ws2ylmnQ".to_string(), sign_client_data, reg.pub_key, response.into_data().unwrap()).unwrap();
+    assert_eq!(auth.counter, 3);
+
+    match Response::decode(&[0x69, 0x85]).unwrap().into_data() {
+        Err(U2fError::ApduStatus(raw::SW_CONDITIONS_NOT_SATISFIED)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+// A 64 byte HID report holding the hex encoded prefix, zero padded.
+fn hid_packet(prefix: &str) -> hid::Packet {
+    let mut packet = [0u8; hid::HID_RPT_SIZE];
+    let prefix = hex::decode(prefix).unwrap();
+    packet[..prefix.len()].copy_from_slice(&prefix);
+    packet
+}
+
+#[test]
+fn test_hid_framing() {
+    // Channel allocation and U2F_VERSION, as exchanged with a token.
+    let nonce = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+    let init = Message::init(nonce).to_packets().unwrap();
+    assert_eq!(init, vec![hid_packet("ffffffff8600080102030405060708")]);
+
+    let mut decoder = Decoder::new();
+    let reply = decoder.push(&hid_packet("ffffffff86001101020304050607080003e51a0205010201")).unwrap().unwrap();
+    assert!(InitResponse::decode(&reply, [0; 8]).is_err());
+    let channel = InitResponse::decode(&reply, nonce).unwrap();
+    assert_eq!(channel.cid, 0x0003e51a);
+    assert_eq!((channel.protocol_version, channel.major_version, channel.minor_version, channel.build_version), (2, 5, 1, 2));
+    assert!(channel.supports_wink() && !channel.supports_lock());
+    assert_eq!(InitResponse::decode(&Message::new(hid::CID_BROADCAST, Command::Init, channel.encode()), nonce).unwrap(), channel);
+
+    let version = Message::apdu(channel.cid, &Request::Version).unwrap().to_packets().unwrap();
+    assert_eq!(version, vec![hid_packet("0003e51a8300070003000000000000")]);
+    let reply = decoder.push(&hid_packet("0003e51a8300085532465f56329000")).unwrap().unwrap();
+    assert_eq!(reply.into_response().unwrap().version().unwrap(), "U2F_V2");
+
+    assert_eq!(Message::wink(channel.cid).to_packets().unwrap(), vec![hid_packet("0003e51a880000")]);
+    assert_eq!(Message::lock(channel.cid, 10).to_packets().unwrap(), vec![hid_packet("0003e51a8400010a")]);
+    assert_eq!(Message::sync(channel.cid, 0x42).to_packets().unwrap(), vec![hid_packet("0003e51abc000142")]);
+    let ping = Message::ping(channel.cid, (0..=255).collect());
+    let packets = ping.to_packets().unwrap();
+    assert_eq!(packets.len(), 1 + 4);
+    assert_eq!(&packets[0][..7], &[0x00, 0x03, 0xe5, 0x1a, hid::U2FHID_PING, 0x01, 0x00]);
+    assert_eq!(&packets[4][..5], &[0x00, 0x03, 0xe5, 0x1a, 0x03]);
+    let echoed: Vec<Option<Message>> = packets.iter().map(|packet| decoder.push(packet).unwrap()).collect();
+    assert!(echoed[..4].iter().all(|message| message.is_none()));
+    assert_eq!(echoed[4].as_ref().unwrap(), &ping);
+
+    // Registration over U2FHID: the request spans a continuation packet, the reply several.
+    let app_id = "https://u2f.bin.coffee";
+    let client_data = r#"{"challenge":"x2ihLZaIcGhA-ByY2mgLc8aofEM","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+    let register = Message::apdu(channel.cid, &Request::register(app_id, client_data.as_bytes())).unwrap();
+    let packets = register.to_packets().unwrap();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(&packets[0][..14], &hex::decode("0003e51a83004900010300000040").unwrap()[..]);
+    assert_eq!(&packets[1][..5], &[0x00, 0x03, 0xe5, 0x1a, 0x00]);
+    let mut device = Decoder::new();
+    assert!(device.push(&packets[0]).unwrap().is_none());
+    let received = device.push(&packets[1]).unwrap().unwrap();
+    assert_eq!(Request::decode(&received.data).unwrap(), Request::register(app_id, client_data.as_bytes()));
+
+    let registration_data = base64::decode_config(KRYPTON_REGISTRATION_DATA, base64::URL_SAFE_NO_PAD).unwrap();
+    let reply = Message::new(channel.cid, Command::Msg, Response::new(registration_data, raw::SW_NO_ERROR).encode());
+    let mut packets = reply.to_packets().unwrap();
+    // Traffic of another application on the same device is interleaved.
+    packets.insert(2, hid_packet("0badcafe0000"));
+
+    let mut reassembled = None;
+    for packet in &packets {
+        if let Some(message) = decoder.push(packet).unwrap() {
+            reassembled = Some(message);
+        }
+    }
+    let data = reassembled.unwrap().into_response().unwrap().into_data().unwrap();
+    let reg = parse_registration(app_id.to_string(), client_data.as_bytes().to_vec(), data.clone()).unwrap();
+    assert_eq!(reg.subject().unwrap(), "Krypton Key");
+
+    // A second channel starting a transaction mid-message is busy and cannot disturb it.
+    let other = Message::ping(0x0badcafe, (0..100).collect()).to_packets().unwrap();
+    let mut decoder = Decoder::new();
+    assert!(decoder.push(&packets[0]).unwrap().is_none());
+    match decoder.push(&other[0]) {
+        Err(U2fError::HidError(hid::ERR_CHANNEL_BUSY)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(decoder.push(&other[1]).unwrap().is_none());
+    let mut reassembled = None;
+    for packet in &packets[1..] {
+        if let Some(message) = decoder.push(packet).unwrap() {
+            reassembled = Some(message);
+        }
+    }
+    assert_eq!(reassembled.unwrap().into_response().unwrap().into_data().unwrap(), data);
+    let echoed: Vec<Option<Message>> = other.iter().map(|packet| decoder.push(packet).unwrap()).collect();
+    assert_eq!(echoed.last().unwrap().as_ref().unwrap().data, (0..100).collect::<Vec<u8>>());
+
+    // Out of order continuation packets and device errors.
+    let mut decoder = Decoder::new();
+    assert!(decoder.push(&packets[0]).unwrap().is_none());
+    match decoder.push(&packets[3]) {
+        Err(U2fError::HidError(hid::ERR_INVALID_SEQ)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match decoder.push(&packets[1]) {
+        Err(U2fError::InvalidHidPacket) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match decoder.push(&hid_packet("0003e51abf000106")).unwrap().unwrap().into_response() {
+        Err(U2fError::HidError(hid::ERR_CHANNEL_BUSY)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(Message::ping(channel.cid, vec![0; hid::MAX_MESSAGE_SIZE + 1]).to_packets().is_err());
+}
+
+#[test]
+fn test_ble_framing() {
+    // U2F_VERSION with a 20 byte control point, then a reply preceded by keepalives.
+    let version = ble::Message::apdu(&Request::Version).unwrap().to_fragments(20).unwrap();
+    assert_eq!(version, vec![hex::decode("83000700030000000000").unwrap()]);
+
+    let mut decoder = ble::Decoder::new(20).unwrap();
+    let waiting = decoder.push(&hex::decode("82000102").unwrap()).unwrap().unwrap();
+    assert_eq!(waiting.keepalive_status(), Some(ble::KEEPALIVE_TUP_NEEDED));
+    let reply = decoder.push(&hex::decode("8300085532465f56329000").unwrap()).unwrap().unwrap();
+    assert_eq!(reply.keepalive_status(), None);
+    assert_eq!(reply.into_response().unwrap().version().unwrap(), "U2F_V2");
+
+    // Authentication: a 138 byte APDU spread over eight fragments.
+    let (reg, key) = make_registration();
+    let app_id = "https://u2f.bin.coffee";
+    let sign = make_sign_response(&key, &reg.key_handle, app_id, "bRLh0fxu3DvMr5ws2ylmnQ", 0x01, 9);
+    let client_data = base64::decode_config(&sign.client_data, base64::URL_SAFE_NO_PAD).unwrap();
+    let request = Request::authenticate(AuthenticateControl::EnforceUserPresence, app_id, &client_data, &reg.key_handle);
+
+    let fragments = ble::Message::apdu(&request).unwrap().to_fragments(20).unwrap();
+    assert_eq!(fragments.len(), 8);
+    assert_eq!(&fragments[0][..10], &hex::decode("83008a00020300000081").unwrap()[..]);
+    assert!(fragments.iter().all(|fragment| fragment.len() <= 20));
+    assert_eq!(fragments.iter().skip(1).map(|fragment| fragment[0]).collect::<Vec<u8>>(), vec![0, 1, 2, 3, 4, 5, 6]);
+    let mut device = ble::Decoder::new(20).unwrap();
+    let received: Vec<Option<ble::Message>> = fragments.iter().map(|fragment| device.push(fragment).unwrap()).collect();
+    assert_eq!(Request::decode(&received[7].as_ref().unwrap().data).unwrap(), request);
+
+    let signature_data = base64::decode_config(&sign.signature_data, base64::URL_SAFE_NO_PAD).unwrap();
+    let reply = ble::Message::new(ble::Command::Msg, Response::new(signature_data, raw::SW_NO_ERROR).encode());
+    let mut reassembled = None;
+    for fragment in reply.to_fragments(20).unwrap() {
+        reassembled = decoder.push(&fragment).unwrap();
+    }
+    let data = reassembled.unwrap().into_response().unwrap().into_data().unwrap();
+    let auth = parse_sign_response(app_id.to_string(), "bRLh0fxu3DvMr5ws2ylmnQ".to_string(), client_data, reg.pub_key, data).unwrap();
+    assert_eq!(auth.counter, 9);
+
+    // Sequence numbers wrap after 0x7f.
+    let ping = ble::Message::ping(vec![0x5a; 17 + 130 * 19]);
+    let fragments = ping.to_fragments(20).unwrap();
+    assert_eq!((fragments[128][0], fragments[129][0]), (0x7f, 0x00));
+    let mut decoder = ble::Decoder::new(20).unwrap();
+    let echoed: Vec<Option<ble::Message>> = fragments.iter().map(|fragment| decoder.push(fragment).unwrap()).collect();
+    assert_eq!(echoed.last().unwrap().as_ref().unwrap(), &ping);
+
+    // Malformed sequences and errors.
+    assert!(ble::Decoder::new(19).is_err());
+    assert!(ping.to_fragments(513).is_err());
+    let mut decoder = ble::Decoder::new(20).unwrap();
+    assert!(decoder.push(&fragments[0]).unwrap().is_none());
+    match decoder.push(&fragments[2]) {
+        Err(U2fError::BleError(ble::ERR_INVALID_SEQ)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match decoder.push(&fragments[1]) {
+        Err(U2fError::InvalidBleFrame) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match decoder.push(&hex::decode("bf000101").unwrap()).unwrap().unwrap().into_response() {
+        Err(U2fError::BleError(ble::ERR_INVALID_CMD)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+// Replays a recorded NFC exchange, checking every command sent.
+struct MockCard(std::collections::VecDeque<(Vec<u8>, Vec<u8>)>);
+
+impl nfc::Transport for MockCard {
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>, U2fError> {
+        let (command, response) = self.0.pop_front().expect("unexpected command");
+        assert_eq!(hex::encode(apdu), hex::encode(command));
+        Ok(response)
+    }
+}
+
+#[test]
+fn test_nfc_session() {
+    let app_id = "https://u2f.bin.coffee";
+    let client_data = r#"{"challenge":"x2ihLZaIcGhA-ByY2mgLc8aofEM","origin":"https://u2f.bin.coffee","typ":"navigator.id.finishEnrollment"}"#;
+    let registration_data = base64::decode_config(KRYPTON_REGISTRATION_DATA, base64::URL_SAFE_NO_PAD).unwrap();
+    assert!(registration_data.len() > 256);
+
+    let select = hex::decode("00a4040008a0000006472f000100").unwrap();
+    let register = Request::register(app_id, client_data.as_bytes()).encode(ApduEncoding::Short).unwrap();
+    assert_eq!(&register[..5], &[0x00, 0x01, 0x03, 0x00, 0x40]);
+    let get_response = |le: u8| vec![0x00, nfc::INS_GET_RESPONSE, 0x00, 0x00, le];
+    let chunk = |data: &[u8], sw: &[u8]| [data, sw].concat();
+
+    let remaining = registration_data.len() - 256;
+    let card = MockCard(vec![
+        (select.clone(), b"U2F_V2\x90\x00".to_vec()),
+        (register.clone(), chunk(&registration_data[..200], &[0x61, 0x00])),
+        (get_response(0x00), chunk(&registration_data[200..256], &[0x61, remaining as u8])),
+        (get_response(remaining as u8), chunk(&registration_data[256..], &[0x90, 0x00])),
+        (Request::Version.encode(ApduEncoding::Short).unwrap(), b"U2F_V2\x90\x00".to_vec()),
+    ].into_iter().collect());
+
+    let mut session = NfcSession::new(card);
+    let data = session.register(app_id, client_data.as_bytes()).unwrap();
+    let reg = parse_registration(app_id.to_string(), client_data.as_bytes().to_vec(), data).unwrap();
+    assert_eq!(reg.subject().unwrap(), "Krypton Key");
+    assert_eq!(session.version().unwrap(), "U2F_V2");
+    assert!(session.into_inner().0.is_empty());
+
+    // Status words are reported as errors, an unknown applet included.
+    let card = MockCard(vec![
+        (select.clone(), vec![0x90, 0x00]),
+        (register.clone(), vec![0x69, 0x85]),
+    ].into_iter().collect());
+    let mut session = NfcSession::new(card);
+    match session.register(app_id, client_data.as_bytes()) {
+        Err(U2fError::ApduStatus(raw::SW_CONDITIONS_NOT_SATISFIED)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let card = MockCard(vec![(select, vec![0x6a, 0x82])].into_iter().collect());
+    match NfcSession::new(card).select() {
+        Err(U2fError::ApduStatus(0x6a82)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_soft_token_ceremony() {
+    let app_id = "https://example.com/u2f/app-id.json";
+    let (root, root_key) = make_cert("Soft Token Root", None, true);
+    let (cert, cert_key) = make_cert("Soft Token Attestation", Some(("Soft Token Root", &root_key)), false);
+
+    let mut store = AttestationTrustStore::new();
+    store.add_root_der(&root).unwrap();
+    let u2f = U2f::new(app_id.into()).with_attestation(store, AttestationPolicy::TrustedChain);
+
+    let mut token = SoftToken::new()
+        .unwrap()
+        .with_attestation(&cert, &der::sequence(&[&der::integer(&[1]), &der::tlv(der::OCTET_STRING, &cert_key)]))
+        .unwrap()
+        .with_counter(41);
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.register(&u2f.request(challenge.clone(), vec![]).unwrap()).unwrap();
+    let reg = u2f.register_response(challenge, response).unwrap();
+    assert_eq!(reg.subject().unwrap(), "Soft Token Attestation");
+
+    // A second registration of the same token is refused.
+    let challenge = u2f.generate_challenge().unwrap();
+    match token.register(&u2f.request(challenge, vec![reg.clone()]).unwrap()) {
+        Err(U2fError::DeviceIneligible) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.sign(&u2f.sign_request(challenge.clone(), vec![reg.clone()])).unwrap();
+    let auth = u2f.sign_response(challenge, reg.clone(), response, 41).unwrap();
+    assert_eq!((auth.counter, auth.user_presence), (42, true));
+    assert_eq!(token.counter(), 42);
+
+    // Keys are bound to the app ID they were registered for.
+    let other = U2f::new("https://example.net".into());
+    let challenge = other.generate_challenge().unwrap();
+    match token.sign(&other.sign_request(challenge, vec![reg.clone()])) {
+        Err(U2fError::DeviceIneligible) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let mut token = token.with_user_presence(false);
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = token.sign(&u2f.sign_request(challenge.clone(), vec![reg.clone()])).unwrap();
+    match u2f.sign_response(challenge, reg, response, 42) {
+        Err(U2fError::InvalidUserPresenceByte) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // The default self-signed attestation is rejected by the trust store.
+    let mut untrusted = SoftToken::new().unwrap();
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = untrusted.register(&u2f.request(challenge.clone(), vec![]).unwrap()).unwrap();
+    match u2f.register_response(challenge, response) {
+        Err(U2fError::NotTrustedAnchor) => (),
+        r => panic!("unexpected result: {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_key_wrapper() {
+    let wrapper = KeyWrapper::new([7; 32]);
+    let application = default_provider().sha256(b"https://example.com");
+    let private_key = [0x42; 32];
+
+    let key_handle = wrapper.wrap(&application, &private_key).unwrap();
+    assert_eq!(key_handle.len(), key_wrapper::KEY_HANDLE_LENGTH);
+    assert!(key_handle.len() <= 255);
+    assert_ne!(wrapper.wrap(&application, &private_key).unwrap(), key_handle);
+    assert_eq!(wrapper.unwrap(&application, &key_handle).unwrap(), private_key.to_vec());
+
+    let mut tampered = key_handle.clone();
+    tampered[20] ^= 0x01;
+    let rejected = [
+        wrapper.unwrap(&default_provider().sha256(b"https://example.net"), &key_handle),
+        KeyWrapper::new([8; 32]).unwrap(&application, &key_handle),
+        wrapper.unwrap(&application, &tampered),
+        wrapper.unwrap(&application, &key_handle[..60]),
+    ];
+    for result in rejected.iter() {
+        match result {
+            Err(U2fError::WrongKeyHandler) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+    match wrapper.wrap(&application, &[0x42; 31]) {
+        Err(U2fError::InvalidPrivateKey) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // Soft tokens sharing a wrapping key are interchangeable; they keep no key material.
+    let app_id = "https://example.com";
+    let u2f = U2f::new(app_id.into());
+    let mut first = SoftToken::new().unwrap().with_key_wrapper(wrapper.clone());
+    let mut second = SoftToken::new().unwrap().with_key_wrapper(wrapper);
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let reg = u2f.register_response(challenge.clone(), first.register(&u2f.request(challenge, vec![]).unwrap()).unwrap()).unwrap();
+    assert_eq!(reg.key_handle.len(), key_wrapper::KEY_HANDLE_LENGTH);
+
+    let challenge = u2f.generate_challenge().unwrap();
+    let response = second.sign(&u2f.sign_request(challenge.clone(), vec![reg.clone()])).unwrap();
+    assert_eq!(u2f.sign_response(challenge, reg.clone(), response, 0).unwrap().counter, 1);
+
+    let challenge = u2f.generate_challenge().unwrap();
+    match SoftToken::new().unwrap().sign(&u2f.sign_request(challenge, vec![reg])) {
+        Err(U2fError::DeviceIneligible) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_crypto_providers() {
+    let (root, root_key) = make_cert("Provider Root", None, true);
+    let (cert, cert_key) = make_cert("Provider Leaf", Some(("Provider Root", &root_key)), false);
+    let (other, _) = make_cert("Provider Root", None, true);
+    let cert_public_key = default_provider().p256_public_key(&cert_key).unwrap();
+
+    let (reg, key) = make_registration();
+    let msg = b"signed message";
+    let signature = default_provider().sign_p256(&key, msg).unwrap();
+    let mut off_curve = reg.pub_key.clone();
+    off_curve[64] ^= 0x01;
+
+    for crypto in crypto_providers() {
+        assert_eq!(hex::encode(crypto.sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+        crypto.random_bytes(&mut a).unwrap();
+        crypto.random_bytes(&mut b).unwrap();
+        assert_ne!(a, b);
+
+        assert!(crypto.verify_p256(&reg.pub_key, msg, &signature).unwrap());
+        assert!(!crypto.verify_p256(&reg.pub_key, b"other message", &signature).unwrap());
+        match crypto.verify_p256(&reg.pub_key, msg, &signature[1..]) {
+            Err(U2fError::BadSignature) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match crypto.verify_p256(&off_curve, msg, &signature) {
+            Err(U2fError::InvalidPublicKey) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let parsed = crypto.parse_certificate(&cert).unwrap();
+        assert_eq!(parsed.subject.as_deref(), Some("Provider Leaf"));
+        assert_eq!(parsed.issuer.as_deref(), Some("Provider Root"));
+        assert_eq!(parsed.p256_public_key.as_ref(), Some(&cert_public_key));
+
+        assert_eq!(parsed.public_key, cert_public_key);
+
+        assert!(crypto.verify_certificate(&cert, &root).unwrap());
+        assert!(crypto.verify_certificate(&root, &root).unwrap());
+        assert!(!crypto.verify_certificate(&cert, &other).unwrap());
+
+        assert_eq!(hex::encode(crypto.sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        // Keys, signatures and sealed data of one backend are understood by the others.
+        let private_key = crypto.generate_p256_key().unwrap();
+        let public_key = crypto.p256_public_key(&private_key).unwrap();
+        let own_signature = crypto.sign_p256(&private_key, msg).unwrap();
+        let (aes_key, nonce) = ([3u8; 32], [4u8; 12]);
+        let sealed = crypto.seal_aes_256_gcm(&aes_key, &nonce, b"aad", b"plaintext").unwrap();
+        assert_eq!(sealed.len(), b"plaintext".len() + 16);
+
+        for other_crypto in crypto_providers() {
+            assert_eq!(other_crypto.p256_public_key(&private_key).unwrap(), public_key);
+            assert!(other_crypto.verify_p256(&public_key, msg, &own_signature).unwrap());
+            assert!(other_crypto.verify_signature(&cert, msg, &other_crypto.sign_p256(&cert_key, msg).unwrap()).unwrap());
+            assert_eq!(other_crypto.open_aes_256_gcm(&aes_key, &nonce, b"aad", &sealed).unwrap(), b"plaintext".to_vec());
+            match other_crypto.open_aes_256_gcm(&aes_key, &nonce, b"other aad", &sealed) {
+                Err(U2fError::EncryptionError) => (),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_challenge_store() {
+    let app_id = "https://example.com";
+    let dir = std::env::temp_dir().join(format!("u2f-challenges-{}", std::process::id()));
+    let stores: Vec<Arc<dyn ChallengeStore>> = vec![
+        Arc::new(MemoryChallengeStore::new()),
+        Arc::new(FileChallengeStore::new(&dir).unwrap()),
+    ];
+
+    for store in stores {
+        let u2f = U2f::new(app_id.into()).with_challenge_store(store.clone());
+        let mut token = SoftToken::new().unwrap();
+
+        // A response that fails verification does not use up the challenge.
+        let challenge = u2f.generate_challenge().unwrap();
+        let response = token.register(&u2f.request(challenge.clone(), vec![]).unwrap()).unwrap();
+        let forged = RegisterResponse { registration_data: "AAAA".into(), ..response.clone() };
+        assert!(u2f.register_response(challenge.clone(), forged).is_err());
+        let reg = u2f.register_response(challenge.clone(), response.clone()).unwrap();
+        match u2f.register_response(challenge, response) {
+            Err(U2fError::ChallengeReplayed) => (),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let challenge = u2f.generate_challenge().unwrap();
+        let response = token.sign(&u2f.sign_request(challenge.clone(), vec![reg.clone()])).unwrap();
+        let forged = SignResponse { signature_data: "AAAA".into(), ..response.clone() };
+        assert!(u2f.sign_response(challenge.clone(), reg.clone(), forged, 0).is_err());
+        u2f.sign_response(challenge.clone(), reg.clone(), response.clone(), 0).unwrap();
+        match u2f.sign_response(challenge, reg, response, 1) {
+            Err(U2fError::ChallengeReplayed) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Challenges the store never issued are refused like replays.
+        let now = chrono::Utc::now();
+        let forged = Challenge::from_bytes(app_id.into(), &[7; 32], now);
+        match store.consume(&forged, now) {
+            Err(U2fError::ChallengeReplayed) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The same challenge string issued for another app ID is a different challenge.
+        let challenge = u2f.generate_challenge().unwrap();
+        let other = Challenge { app_id: "https://example.net".into(), ..challenge.clone() };
+        match store.consume(&other, now) {
+            Err(U2fError::ChallengeReplayed) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        store.consume(&challenge, now).unwrap();
+
+        let late = now + chrono::Duration::seconds(301);
+        let challenge = u2f.generate_challenge().unwrap();
+        match store.consume(&challenge, late) {
+            Err(U2fError::ChallengeExpired) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let challenge = u2f.generate_challenge().unwrap();
+        store.expire(late).unwrap();
+        match store.consume(&challenge, now) {
+            Err(U2fError::ChallengeReplayed) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -17,7 +17,35 @@ pub enum U2fError {
     CounterTooLow,
     OpenSSLNoCurveName,
     InvalidPublicKey,
+    #[cfg(feature = "openssl")]
     OpenSSLError(openssl::error::ErrorStack),
+    ChallengeMismatch,
+    OriginMismatch,
+    InvalidClientDataType,
+    InvalidTimestamp,
+    ChallengeNotYetValid,
+    InvalidRegistrationData,
+    TruncatedPublicKey,
+    TruncatedKeyHandle,
+    InvalidCertificateLength,
+    MissingSignature,
+    IoError(std::io::Error),
+    InvalidMetadataBlob,
+    MissingCounter,
+    InvalidChallenge,
+    InvalidAppId,
+    InvalidFacet,
+    InvalidApdu,
+    ApduStatus(u16),
+    InvalidHidPacket,
+    HidError(u8),
+    InvalidBleFrame,
+    BleError(u8),
+    DeviceIneligible,
+    InvalidPrivateKey,
+    UnsupportedAlgorithm,
+    EncryptionError,
+    ChallengeReplayed,
 }
 
 impl fmt::Display for U2fError {
@@ -37,7 +65,35 @@ impl fmt::Display for U2fError {
             U2fError::CounterTooLow => write!(f, "Counter too low"),
             U2fError::InvalidPublicKey => write!(f, "Invalid public key"),
             U2fError::OpenSSLNoCurveName => write!(f, "OpenSSL no curve name"),
+            #[cfg(feature = "openssl")]
             U2fError::OpenSSLError(e) => e.fmt(f),
+            U2fError::ChallengeMismatch => write!(f, "Challenge mismatch"),
+            U2fError::OriginMismatch => write!(f, "Origin mismatch"),
+            U2fError::InvalidClientDataType => write!(f, "Invalid Client Data type"),
+            U2fError::InvalidTimestamp => write!(f, "Invalid Timestamp"),
+            U2fError::ChallengeNotYetValid => write!(f, "Challenge not yet valid"),
+            U2fError::InvalidRegistrationData => write!(f, "Invalid Registration Data"),
+            U2fError::TruncatedPublicKey => write!(f, "Truncated public key"),
+            U2fError::TruncatedKeyHandle => write!(f, "Truncated key handle"),
+            U2fError::InvalidCertificateLength => write!(f, "Invalid certificate length"),
+            U2fError::MissingSignature => write!(f, "Missing signature"),
+            U2fError::IoError(e) => e.fmt(f),
+            U2fError::InvalidMetadataBlob => write!(f, "Invalid Metadata BLOB"),
+            U2fError::MissingCounter => write!(f, "Missing counter"),
+            U2fError::InvalidChallenge => write!(f, "Invalid Challenge"),
+            U2fError::InvalidAppId => write!(f, "Invalid AppID"),
+            U2fError::InvalidFacet => write!(f, "Invalid FacetID"),
+            U2fError::InvalidApdu => write!(f, "Invalid APDU"),
+            U2fError::ApduStatus(status) => write!(f, "APDU status word {:04X}", status),
+            U2fError::InvalidHidPacket => write!(f, "Invalid U2FHID packet"),
+            U2fError::HidError(code) => write!(f, "U2FHID error {:02X}", code),
+            U2fError::InvalidBleFrame => write!(f, "Invalid BLE frame"),
+            U2fError::BleError(code) => write!(f, "BLE error {:02X}", code),
+            U2fError::DeviceIneligible => write!(f, "Device ineligible"),
+            U2fError::InvalidPrivateKey => write!(f, "Invalid private key"),
+            U2fError::UnsupportedAlgorithm => write!(f, "Unsupported algorithm"),
+            U2fError::EncryptionError => write!(f, "Encryption error"),
+            U2fError::ChallengeReplayed => write!(f, "Challenge replayed"),
         }
     }
 }
@@ -59,7 +115,35 @@ impl error::Error for U2fError {
             U2fError::CounterTooLow => "Counter too low",
             U2fError::InvalidPublicKey => "Invalid public key",
             U2fError::OpenSSLNoCurveName => "OpenSSL no curve name",
-            U2fError::OpenSSLError(e) => e.description(),
+            #[cfg(feature = "openssl")]
+            U2fError::OpenSSLError(_) => "OpenSSL error",
+            U2fError::ChallengeMismatch => "Client data challenge does not match the issued challenge",
+            U2fError::OriginMismatch => "Client data origin is not valid for the app ID",
+            U2fError::InvalidClientDataType => "Client data type does not match the expected operation",
+            U2fError::InvalidTimestamp => "Error attempting to parse challenge timestamp",
+            U2fError::ChallengeNotYetValid => "Challenge timestamp lies in the future beyond the allowed clock skew",
+            U2fError::InvalidRegistrationData => "Error attempting to decode registration data",
+            U2fError::TruncatedPublicKey => "Registration data ends before the user public key",
+            U2fError::TruncatedKeyHandle => "Registration data ends before the key handle",
+            U2fError::InvalidCertificateLength => "Attestation certificate length is malformed or exceeds the registration data",
+            U2fError::MissingSignature => "Registration data carries no signature after the attestation certificate",
+            U2fError::IoError(_) => "Error attempting to read from disk",
+            U2fError::InvalidMetadataBlob => "Error attempting to decode metadata BLOB",
+            U2fError::MissingCounter => "No stored counter was given for the matching registration",
+            U2fError::InvalidChallenge => "Challenge is not valid unpadded base64url",
+            U2fError::InvalidAppId => "AppID is not an https URL below a public suffix",
+            U2fError::InvalidFacet => "FacetID cannot be listed for the AppID",
+            U2fError::InvalidApdu => "Error attempting to decode APDU",
+            U2fError::ApduStatus(_) => "Token returned an error status word",
+            U2fError::InvalidHidPacket => "Error attempting to decode U2FHID packet",
+            U2fError::HidError(_) => "U2FHID transaction failed",
+            U2fError::InvalidBleFrame => "Error attempting to decode BLE frame",
+            U2fError::BleError(_) => "BLE transaction failed",
+            U2fError::DeviceIneligible => "Token holds none of the requested keys, or is already registered",
+            U2fError::InvalidPrivateKey => "Invalid private key",
+            U2fError::UnsupportedAlgorithm => "Signature algorithm not supported by the crypto provider",
+            U2fError::EncryptionError => "Error attempting to seal or open data with AES-GCM",
+            U2fError::ChallengeReplayed => "Challenge was not issued by the challenge store or has already been used",
         }
     }
 
@@ -79,7 +163,35 @@ impl error::Error for U2fError {
             U2fError::CounterTooLow => None,
             U2fError::InvalidPublicKey => None,
             U2fError::OpenSSLNoCurveName => None,
+            #[cfg(feature = "openssl")]
             U2fError::OpenSSLError(_) => None,
+            U2fError::ChallengeMismatch => None,
+            U2fError::OriginMismatch => None,
+            U2fError::InvalidClientDataType => None,
+            U2fError::InvalidTimestamp => None,
+            U2fError::ChallengeNotYetValid => None,
+            U2fError::InvalidRegistrationData => None,
+            U2fError::TruncatedPublicKey => None,
+            U2fError::TruncatedKeyHandle => None,
+            U2fError::InvalidCertificateLength => None,
+            U2fError::MissingSignature => None,
+            U2fError::IoError(_) => None,
+            U2fError::InvalidMetadataBlob => None,
+            U2fError::MissingCounter => None,
+            U2fError::InvalidChallenge => None,
+            U2fError::InvalidAppId => None,
+            U2fError::InvalidFacet => None,
+            U2fError::InvalidApdu => None,
+            U2fError::ApduStatus(_) => None,
+            U2fError::InvalidHidPacket => None,
+            U2fError::HidError(_) => None,
+            U2fError::InvalidBleFrame => None,
+            U2fError::BleError(_) => None,
+            U2fError::DeviceIneligible => None,
+            U2fError::InvalidPrivateKey => None,
+            U2fError::UnsupportedAlgorithm => None,
+            U2fError::EncryptionError => None,
+            U2fError::ChallengeReplayed => None,
         }
     }
 }
\ No newline at end of file
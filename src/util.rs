@@ -1,28 +1,25 @@
 use chrono::prelude::*;
-use time::Duration;
-use openssl::rand;
+use chrono::Duration;
 use bytes::{Bytes};
 use base64::{encode_config, URL_SAFE_NO_PAD};
 use crate::u2ferror::U2fError;
+use crate::messages::{ClientData, TrustedFacetsList};
+use crate::appid::AppId;
 
 /// The `Result` type used in this crate.
 type Result<T> = ::std::result::Result<T, U2fError>;
 
-pub const U2F_V2: &'static str = "U2F_V2";
+pub const U2F_V2: &str = "U2F_V2";
 
-// Generates a challenge from a secure, random source.
-pub fn generate_challenge(size: usize) -> Result<Vec<u8>> {
-    let mut bytes: Vec<u8> = vec![0; size];
-    rand::rand_bytes(&mut bytes).map_err(|_e| U2fError::RandomSecureBytesError)?;
-    Ok(bytes)
-}
-
-pub fn expiration(timestamp: String) -> Duration {
-    let now: DateTime<Utc> = Utc::now();
+// Client data `typ` values for each operation.
+pub const REGISTER_TYPE: &str = "navigator.id.finishEnrollment";
+pub const SIGN_TYPE: &str = "navigator.id.getAssertion";
 
-    let ts = timestamp.parse::<DateTime<Utc>>();
+// Time elapsed between the challenge timestamp and `now`. Negative if the timestamp lies in the future.
+pub fn expiration(timestamp: &str, now: DateTime<Utc>) -> Result<Duration> {
+    let ts = timestamp.parse::<DateTime<Utc>>().map_err(|_e| U2fError::InvalidTimestamp)?;
 
-    now.signed_duration_since(ts.unwrap())
+    Ok(now.signed_duration_since(ts))
 }
 
 // Decode initial bytes of buffer as ASN and return the length of the encoded structure.
@@ -36,26 +33,88 @@ pub fn asn_length(mem: Bytes) -> Result<usize> {
 
     let len = buffer[1]; // Len
     if len & 0x80 == 0 {
-        return Ok((len & 0x7f) as usize);
+        return Ok((len & 0x7f) as usize + 2);
     }
 
-    let numbem_of_bytes = len & 0x7f;
-    if numbem_of_bytes == 0 {
+    let numbem_of_bytes = (len & 0x7f) as usize;
+    if numbem_of_bytes == 0 || numbem_of_bytes > std::mem::size_of::<usize>() {
+        return Err(U2fError::Asm1DecoderError);
+    }
+
+    if buffer.len() < 2 + numbem_of_bytes {
         return Err(U2fError::Asm1DecoderError);
     }
 
     let mut length: usize = 0;
     for num in 0..numbem_of_bytes {
-        length = length*0x100 + (buffer[(2+num) as usize] as usize);
+        length = length.checked_mul(0x100).ok_or(U2fError::Asm1DecoderError)? + (buffer[2 + num] as usize);
     }
- 
-    length = length + (numbem_of_bytes as usize);
 
-    Ok(length + 2) // Add the 2 initial bytes: type and length.
+    // Add the initial bytes: type, length and the length octets.
+    length.checked_add(2 + numbem_of_bytes).ok_or(U2fError::Asm1DecoderError)
 }
 
 pub fn get_encoded(data: &[u8]) -> String {
     let encoded: String = encode_config(data, URL_SAFE_NO_PAD);
 
     encoded.trim_end_matches('=').to_string()
-}
\ No newline at end of file
+}
+
+// Checks that the client data carries the issued challenge, an origin that may act
+// for the app ID and the type expected for the operation. Without `trusted_facets` only
+// origins on the AppID's host may act for it.
+pub fn verify_client_data(client_data: &[u8], typ: &str, challenge: &str, app_id: &AppId, trusted_facets: Option<&TrustedFacetsList>) -> Result<ClientData> {
+    let client_data = ClientData::from_bytes(client_data)?;
+
+    if client_data.typ != typ {
+        return Err(U2fError::InvalidClientDataType);
+    }
+
+    if client_data.challenge != challenge {
+        return Err(U2fError::ChallengeMismatch);
+    }
+
+    let authorized = match trusted_facets {
+        Some(trusted_facets) => trusted_facets.is_authorized(app_id, &client_data.origin),
+        None => app_id.is_authorized(&client_data.origin),
+    };
+    if !authorized {
+        return Err(U2fError::OriginMismatch);
+    }
+
+    Ok(client_data)
+}
+
+// Serde helpers storing bytes as unpadded base64url strings.
+pub mod base64url {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use base64::{decode_config, URL_SAFE_NO_PAD};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::get_encoded(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        decode_config(&encoded, URL_SAFE_NO_PAD).map_err(serde::de::Error::custom)
+    }
+}
+
+pub mod base64url_option {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use base64::{decode_config, URL_SAFE_NO_PAD};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&super::get_encoded(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => decode_config(&encoded, URL_SAFE_NO_PAD).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}